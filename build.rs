@@ -0,0 +1,12 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// the embedded web files are served with the build time as their modification time, or with
+// SOURCE_DATE_EPOCH for reproducible builds
+fn main() {
+    let time = std::env::var("SOURCE_DATE_EPOCH").ok()
+        .and_then(|time| time.parse::<u64>().ok())
+        .unwrap_or_else(|| SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()));
+    println!("cargo:rustc-env=MPV_REMOTE_BUILD_TIME={time}");
+    println!("cargo:rerun-if-changed=www");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+}
@@ -0,0 +1,258 @@
+use std::collections::HashMap;
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufRead, AsyncWrite};
+use anyhow::Result;
+use crate::config::Config;
+use crate::limits;
+use crate::mpv::CmdHandle;
+use crate::plugin::{self, WebEvent};
+use crate::server::{self, Method, Request, Response};
+
+/// error returned to the client as the response status and message
+type ApiError = (u16, String);
+
+/// Serves `/api/...`, mapping each endpoint onto the websocket command of the same name. Parameters
+/// are taken from the query string and from a JSON object or form encoded request body.
+pub async fn handle<T>(request: &Request, url: &[&str], stream: &mut T, config: &Config, cmd_handle: &mut CmdHandle<'_>) -> Result<()>
+where
+    T: AsyncBufRead + AsyncWrite + Unpin,
+{
+    let Some(body) = server::read_body(request, stream, config.max_body_size).await? else { return Ok(()) };
+    let result = match request_event(request, url, &body).and_then(|event| rate_limit(request, config, &event).map(|_| event)) {
+        Ok(event) => plugin::execute(event, cmd_handle, config).await.map_err(|e| (400, e.to_string())),
+        Err(e) => Err(e),
+    };
+    let (status, payload) = match result {
+        Ok(reply) => (200, json!({
+            "ok": true,
+            "data": reply.and_then(|r| r.data),
+        })),
+        Err((status, error)) => (status, json!({
+            "ok": false,
+            "error": error,
+        })),
+    };
+    let payload = payload.to_string();
+    let mut response = Response::new("HTTP/1.1", status)
+        .headers(&request.response_headers)
+        .header("Content-Type", "application/json");
+    if status == 429 {
+        response = response.header("Retry-After", "1");
+    }
+    let response = response.body(payload.as_bytes());
+    response.write_to(stream).await?;
+    Ok(())
+}
+
+/// the command an API request stands for, with its parameters
+fn request_event(request: &Request, url: &[&str], body: &[u8]) -> Result<WebEvent, ApiError> {
+    let params = match url {
+        // the body is the playlist document itself
+        ["playlist", "import"] => request.query(),
+        _ => params(request, body)?,
+    };
+    to_event(request, url, &params, body)
+}
+
+fn params(request: &Request, body: &[u8]) -> Result<HashMap<String, String>, ApiError> {
+    let mut params = request.query();
+    if body.is_empty() {
+        return Ok(params);
+    }
+    let content_type = request.header("Content-Type").map_or("", |t| t.as_str());
+    if content_type.starts_with("application/json") {
+        let fields: serde_json::Map<String, Value> = serde_json::from_slice(body)
+            .map_err(|e| (400, format!("invalid json body: {e}")))?;
+        for (key, val) in fields {
+            let val = match val {
+                Value::String(s) => s,
+                val => val.to_string(),
+            };
+            params.insert(key, val);
+        }
+    } else {
+        let body = std::str::from_utf8(body).map_err(|_| (400, "request body is not utf-8".to_string()))?;
+        params.extend(server::parse_query(body));
+    }
+    Ok(params)
+}
+
+/// Only commands count against the client's limit, reading the status does not.
+fn rate_limit(request: &Request, config: &Config, event: &WebEvent) -> Result<(), ApiError> {
+    if event.event == "get-status" || limits::command(config, limits::Client::Address(request.peer)) {
+        Ok(())
+    } else {
+        Err((429, "too many commands, slow down".into()))
+    }
+}
+
+fn expect_method(request: &Request, method: Method) -> Result<(), ApiError> {
+    if request.method == method {
+        Ok(())
+    } else {
+        Err((405, format!("expected {method:?} got {:?}", request.method)))
+    }
+}
+
+fn number(query: &HashMap<String, String>, key: &str) -> Result<Option<Value>, ApiError> {
+    match query.get(key) {
+        Some(n) => {
+            let n: f64 = n.parse().map_err(|_| (400, format!("\"{key}\" is not a number")))?;
+            Ok(Some(json!(n)))
+        },
+        None => Ok(None),
+    }
+}
+
+fn integer(s: &str) -> Result<i64, ApiError> {
+    s.parse().map_err(|_| (400, format!("\"{s}\" is not an integer")))
+}
+
+/// `play-now` and `playlist-add` take either `?url=` or a file picker `?dir=&name=`
+fn load_target(query: &HashMap<String, String>) -> Result<Value, ApiError> {
+    if let Some(url) = query.get("url") {
+        return Ok(json!({ "url": url }));
+    }
+    match (query.get("dir"), query.get("name")) {
+        (Some(dir), Some(name)) => Ok(json!({ "file": { "dir": dir, "name": name } })),
+        _ => Err((400, "expected \"url\" or \"dir\" and \"name\" parameters".into())),
+    }
+}
+
+fn to_event(request: &Request, url: &[&str], query: &HashMap<String, String>, body: &[u8]) -> Result<WebEvent, ApiError> {
+    let event = |event: &str, data: Option<Value>| WebEvent { event: event.into(), data };
+    match url {
+        ["status"] => {
+            expect_method(request, Method::GET)?;
+            Ok(event("get-status", None))
+        },
+        [cmd @ ("toggle-play" | "toggle-fullscreen" | "stop" | "shutdown")] => {
+            expect_method(request, Method::POST)?;
+            Ok(event(cmd, None))
+        },
+        ["seek"] => {
+            expect_method(request, Method::POST)?;
+            let data = match (number(query, "relative")?, number(query, "absolute")?) {
+                (Some(n), _) => json!({ "relative": n }),
+                (None, Some(n)) => json!({ "absolute": n }),
+                (None, None) => return Err((400, "expected \"relative\" or \"absolute\" parameter".into())),
+            };
+            Ok(event("seek", Some(data)))
+        },
+        ["skip", dir @ ("next" | "prev")] => {
+            expect_method(request, Method::POST)?;
+            Ok(event("skip", Some(json!(dir))))
+        },
+        ["volume", rest @ ..] => {
+            expect_method(request, Method::PUT)?;
+            let vol = match rest {
+                [vol] => *vol,
+                _ => query.get("value").map(|v| v.as_str()).ok_or((400, "expected \"value\" parameter".into()))?,
+            };
+            Ok(event("volume", Some(json!(integer(vol)?.to_string()))))
+        },
+        ["play-now"] => {
+            expect_method(request, Method::POST)?;
+            Ok(event("play-now", Some(load_target(query)?)))
+        },
+        ["playlist"] => {
+            expect_method(request, Method::POST)?;
+            Ok(event("playlist-add", Some(load_target(query)?)))
+        },
+        ["sub-add"] => {
+            expect_method(request, Method::POST)?;
+            let (Some(dir), Some(name)) = (query.get("dir"), query.get("name")) else {
+                return Err((400, "expected \"dir\" and \"name\" parameters".into()));
+            };
+            let flag = query.get("flag").map_or("select", |f| f.as_str());
+            Ok(event("sub-add", Some(json!({ "file": { "dir": dir, "name": name }, "flag": flag }))))
+        },
+        ["playlist", "import"] => {
+            expect_method(request, Method::POST)?;
+            let mut data = match (query.get("dir"), query.get("name")) {
+                (Some(dir), Some(name)) => json!({ "file": { "dir": dir, "name": name } }),
+                _ => {
+                    let document = std::str::from_utf8(body).map_err(|_| (400, "playlist is not utf-8".to_string()))?;
+                    json!({ "document": document })
+                },
+            };
+            if let Some(mode) = query.get("mode") {
+                data["mode"] = json!(mode);
+            }
+            Ok(event("playlist-import", Some(data)))
+        },
+        ["playlist", idx] => {
+            let idx = integer(idx)?;
+            match request.method {
+                Method::DELETE => Ok(event("playlist-remove", Some(json!(idx)))),
+                Method::PUT => {
+                    let to = query.get("to").ok_or((400, "expected \"to\" parameter".into()))?;
+                    Ok(event("playlist-move", Some(json!([idx, integer(to)?]))))
+                },
+                _ => Err((405, format!("expected DELETE or PUT got {:?}", request.method))),
+            }
+        },
+        path => Err((404, format!("unknown endpoint \"/api/{}\"", path.join("/")))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::BufReader;
+
+    /// the event for `line`, e.g. "POST /api/seek?relative=5", sent with `body`, or the error status
+    async fn event(line: &str, content_type: &str, body: &str) -> Result<Value, u16> {
+        let raw = format!("{line} HTTP/1.1\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\n\r\n", body.len());
+        let request = Request::parse(&mut BufReader::new(raw.as_bytes())).await.unwrap();
+        let url = server::parse_url(request.route());
+        request_event(&request, &url[2 ..], body.as_bytes())
+            .map(|event| serde_json::to_value(event).unwrap())
+            .map_err(|(status, _)| status)
+    }
+
+    #[tokio::test]
+    async fn events() {
+        let seek = Ok(json!({ "event": "seek", "data": { "relative": 5.0 } }));
+        let move_entry = Ok(json!({ "event": "playlist-move", "data": [2, 0] }));
+        let cases = [
+            // query, JSON and form parameters
+            (("POST /api/seek?relative=5", "", ""), seek.clone()),
+            (("POST /api/seek", "application/json", r#"{"relative": 5}"#), seek.clone()),
+            (("POST /api/seek", "application/x-www-form-urlencoded", "relative=5"), seek.clone()),
+            (("POST /api/seek?absolute=1", "application/json", r#"{"relative": "5"}"#), seek),
+            (("GET /api/status", "", ""), Ok(json!({ "event": "get-status", "data": null }))),
+            (("PUT /api/volume/40", "", ""), Ok(json!({ "event": "volume", "data": "40" }))),
+            (("PUT /api/volume", "application/x-www-form-urlencoded", "value=40"), Ok(json!({ "event": "volume", "data": "40" }))),
+            (("POST /api/play-now?dir=%2Fmusic&name=a%20b.mp3", "", ""),
+                Ok(json!({ "event": "play-now", "data": { "file": { "dir": "/music", "name": "a b.mp3" } } }))),
+            // playlist entries are moved and removed by index
+            (("PUT /api/playlist/2?to=0", "", ""), move_entry.clone()),
+            (("PUT /api/playlist/2", "application/json", r#"{"to": 0}"#), move_entry),
+            (("DELETE /api/playlist/3", "", ""), Ok(json!({ "event": "playlist-remove", "data": 3 }))),
+            // the import body is the playlist document, not parameters
+            (("POST /api/playlist/import?mode=append", "audio/x-mpegurl", "#EXTM3U\na.mp3\n"),
+                Ok(json!({ "event": "playlist-import", "data": { "document": "#EXTM3U\na.mp3\n", "mode": "append" } }))),
+            (("POST /api/playlist/import?dir=%2Fmusic&name=list.m3u", "", ""),
+                Ok(json!({ "event": "playlist-import", "data": { "file": { "dir": "/music", "name": "list.m3u" } } }))),
+            // wrong methods and unknown endpoints
+            (("GET /api/seek?relative=5", "", ""), Err(405)),
+            (("POST /api/status", "", ""), Err(405)),
+            (("POST /api/playlist/2", "", ""), Err(405)),
+            (("POST /api/rewind", "", ""), Err(404)),
+            (("GET /api/skip/sideways", "", ""), Err(404)),
+            // missing or invalid parameters
+            (("POST /api/seek", "", ""), Err(400)),
+            (("POST /api/seek?relative=soon", "", ""), Err(400)),
+            (("POST /api/seek", "application/json", "{relative"), Err(400)),
+            (("PUT /api/volume/loud", "", ""), Err(400)),
+            (("POST /api/play-now?dir=%2Fmusic", "", ""), Err(400)),
+            (("PUT /api/playlist/2", "", ""), Err(400)),
+            (("PUT /api/playlist/two?to=0", "", ""), Err(400)),
+            (("POST /api/sub-add?name=a.srt", "", ""), Err(400)),
+        ];
+        for ((line, content_type, body), expected) in cases {
+            assert_eq!(event(line, content_type, body).await, expected, "{line} {body}");
+        }
+    }
+}
@@ -0,0 +1,144 @@
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use mpv_client::Node;
+use tokio::io::{AsyncRead, AsyncWrite};
+use anyhow::{Result, bail};
+use crate::{assets, screenshot};
+use crate::logger::warning;
+use crate::mpv::CmdHandle;
+use crate::server::{Method, Request, Response, json_error};
+
+/// cover file names looked for next to the playing file, in order of preference
+const COVER_NAMES: &[&str] = &["cover", "folder", "front", "albumart", "album"];
+const COVER_EXTENSIONS: &[&str] = &["jpg", "jpeg", "png", "webp"];
+
+/// whether the loaded file has artwork, set by `refresh` so status payloads do not scan directories
+static AVAILABLE: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug)]
+pub enum Artwork {
+    /// a picture attached to the file, shown by mpv as the selected video track
+    Embedded,
+    File(PathBuf),
+}
+
+/// Looks for artwork of the current file: attached picture tracks first, external ones included,
+/// then cover images in the file's directory, which is read on a blocking thread.
+pub async fn find(cmd_handle: &mut CmdHandle<'_>) -> Option<Artwork> {
+    if let Ok(Node::Array(tracks)) = cmd_handle.get_property::<Node>("track-list") {
+        for track in tracks {
+            let Node::Map(track) = track else { continue };
+            if !matches!(track.get("albumart"), Some(Node::Bool(true))) {
+                continue;
+            }
+            if let Some(Node::String(file)) = track.get("external-filename") {
+                return Some(Artwork::File(file.into()));
+            }
+            if matches!(track.get("selected"), Some(Node::Bool(true))) {
+                return Some(Artwork::Embedded);
+            }
+        }
+    }
+    let path = cmd_handle.get_property::<String>("path").ok()?;
+    if path.contains("://") {
+        return None;
+    }
+    let mut path = PathBuf::from(path);
+    if path.is_relative() {
+        path = Path::new(&cmd_handle.get_property::<String>("working-directory").ok()?).join(path);
+    }
+    let dir = path.parent()?.to_path_buf();
+    tokio::task::spawn_blocking(move || cover_file(&dir)).await.ok()?.map(Artwork::File)
+}
+
+/// Looks for artwork of the file that was just loaded.
+pub async fn refresh(mut cmd_handle: CmdHandle<'static>) {
+    AVAILABLE.store(find(&mut cmd_handle).await.is_some(), Ordering::Relaxed);
+}
+
+/// forgets the artwork of the file that ended, until `refresh` looks at the next one
+pub fn clear() {
+    AVAILABLE.store(false, Ordering::Relaxed);
+}
+
+/// whether the file last loaded has artwork, as found by `refresh`
+pub fn available() -> bool {
+    AVAILABLE.load(Ordering::Relaxed)
+}
+
+/// the preferred cover image in `dir`, names are matched case-insensitively
+fn cover_file(dir: &Path) -> Option<PathBuf> {
+    let files: Vec<(String, PathBuf)> = std::fs::read_dir(dir).ok()?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_ok_and(|t| t.is_file()))
+        .map(|entry| (entry.file_name().to_string_lossy().to_lowercase(), entry.path()))
+        .collect();
+    COVER_NAMES.iter()
+        .flat_map(|name| COVER_EXTENSIONS.iter().map(move |ext| format!("{name}.{ext}")))
+        .find_map(|wanted| files.iter().find(|(name, _)| *name == wanted).map(|(_, path)| path.clone()))
+}
+
+/// the image data, its length and its content type
+async fn load(cmd_handle: &mut CmdHandle<'_>) -> Result<(Box<dyn AsyncRead + Send + Unpin>, u64, &'static str)> {
+    match find(cmd_handle).await {
+        Some(Artwork::File(path)) => {
+            let mime = assets::mime_type(&path);
+            if !mime.starts_with("image/") {
+                bail!("artwork {path:?} is not an image");
+            }
+            let file = tokio::fs::File::open(&path).await?;
+            let len = file.metadata().await?.len();
+            Ok((Box::new(file), len, mime))
+        },
+        Some(Artwork::Embedded) => {
+            // the attached picture is only available decoded, as the current video frame
+            let image = screenshot::capture(cmd_handle, "video")?;
+            let jpeg = tokio::task::spawn_blocking(move || image.jpeg(90)).await??;
+            let len = jpeg.len() as u64;
+            Ok((Box::new(std::io::Cursor::new(jpeg)), len, "image/jpeg"))
+        },
+        None => bail!("no artwork for the current file"),
+    }
+}
+
+/// `GET /api/artwork/current`: cover art of the playing file
+pub async fn handle<T>(request: &Request, stream: &mut T, cmd_handle: &mut CmdHandle<'_>) -> Result<()>
+where
+    T: AsyncWrite + Unpin,
+{
+    let result = match request.method {
+        Method::GET => load(cmd_handle).await.map_err(|e| (404, e.to_string())),
+        _ => Err((405, format!("expected GET got {:?}", request.method))),
+    };
+    match result {
+        Ok((data, len, mime)) => {
+            let response = Response::new("HTTP/1.1", 200)
+                .headers(&request.response_headers)
+                .header("Content-Type", mime)
+                .header("Cache-Control", "no-cache")
+                .reader(data, Some(len));
+            response.write_to(stream).await?;
+        },
+        Err((status, error)) => {
+            warning!("artwork: {error}");
+            json_error(request, stream, status, &error).await?;
+        },
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn cover_preference() {
+        let dir = TempDir::new("artwork");
+        assert_eq!(cover_file(&dir), None);
+        std::fs::write(dir.join("Folder.PNG"), b"").unwrap();
+        assert_eq!(cover_file(&dir), Some(dir.join("Folder.PNG")));
+        std::fs::write(dir.join("cover.jpg"), b"").unwrap();
+        assert_eq!(cover_file(&dir), Some(dir.join("cover.jpg")));
+    }
+}
@@ -0,0 +1,231 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, SystemTime};
+use base64::Engine;
+use sha1::{Digest, Sha1};
+use tokio::fs;
+use crate::config::Config;
+use crate::logger::warning;
+use crate::server::Request;
+
+const INDEX_HTML: &[u8] = include_bytes!("../www/index.html");
+const LOGIN_HTML: &[u8] = include_bytes!("../www/login.html");
+const MAIN_JS: &[u8] = include_bytes!("../www/static/main.js");
+const OUTPUT_CSS: &[u8] = include_bytes!("../www/static/output.css");
+const SYMBOLS_FONT: &[u8] = include_bytes!("../www/static/symbols/material-symbols.woff2");
+
+/// files compiled into the plugin, keyed by their path relative to the web root
+const EMBEDDED: &[(&str, &[u8])] = &[
+    ("index.html", INDEX_HTML),
+    ("login.html", LOGIN_HTML),
+    ("static/main.js", MAIN_JS),
+    ("static/output.css", OUTPUT_CSS),
+    ("static/symbols/material-symbols.woff2", SYMBOLS_FONT),
+];
+
+// embedded assets are compressed on first use and kept for the lifetime of the plugin
+static COMPRESSED: OnceLock<Mutex<HashMap<(&'static str, Encoding), Arc<Vec<u8>>>>> = OnceLock::new();
+
+pub struct Asset {
+    pub data: Cow<'static, [u8]>,
+    pub mime: &'static str,
+    /// weak validator, shared by every content encoding of the asset
+    pub etag: String,
+    pub modified: Option<SystemTime>,
+    /// name of the embedded file this asset was loaded from
+    embedded: Option<&'static str>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Brotli => "br",
+            Self::Gzip => "gzip",
+        }
+    }
+
+    /// Picks the preferred encoding the client accepts, brotli over gzip.
+    pub fn negotiate(accept_encoding: &str) -> Option<Encoding> {
+        let accepted = |name: &str| accept_encoding.split(',').any(|coding| {
+            let mut params = coding.split(';');
+            let coding = params.next().unwrap_or("").trim();
+            let q = params
+                .filter_map(|p| p.trim().strip_prefix("q="))
+                .find_map(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.);
+            coding.eq_ignore_ascii_case(name) && q > 0.
+        });
+        [Self::Brotli, Self::Gzip].into_iter().find(|enc| accepted(enc.name()))
+    }
+
+    fn compress(&self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Self::Brotli => {
+                let mut writer = brotli::CompressorWriter::new(Vec::new(), 4096, 11, 22);
+                writer.write_all(data)?;
+                Ok(writer.into_inner())
+            },
+            Self::Gzip => {
+                let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::best());
+                encoder.write_all(data)?;
+                encoder.finish()
+            },
+        }
+    }
+}
+
+impl Asset {
+    fn new(data: Cow<'static, [u8]>, mime: &'static str, modified: Option<SystemTime>, embedded: Option<&'static str>) -> Self {
+        let hash = Sha1::digest(&data);
+        let etag = format!("W/\"{}\"", base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(&hash[.. 12]));
+        Asset { data, mime, etag, modified, embedded }
+    }
+
+    /// true if the client's cached copy, validated by If-None-Match or If-Modified-Since, is current
+    pub fn not_modified(&self, request: &Request) -> bool {
+        if let Some(tags) = request.header("If-None-Match") {
+            // weak comparison, the W/ prefix is ignored
+            let tag = self.etag.trim_start_matches("W/");
+            return tags.split(',')
+                .map(|t| t.trim().trim_start_matches("W/"))
+                .any(|t| t == "*" || t == tag);
+        }
+        match (self.modified, request.header("If-Modified-Since")) {
+            (Some(modified), Some(since)) => match httpdate::parse_http_date(since) {
+                // http dates only have second precision
+                Ok(since) => modified.duration_since(since).map_or(true, |d| d.as_secs() == 0),
+                Err(_) => false,
+            },
+            _ => false,
+        }
+    }
+
+    /// Compressed copy of an embedded text asset. Files from the web root and already compressed
+    /// formats are always sent as is.
+    pub fn encoded(&self, encoding: Encoding) -> Option<Arc<Vec<u8>>> {
+        let name = self.embedded?;
+        let compressible = self.mime.starts_with("text/")
+            || matches!(self.mime, "application/json" | "application/manifest+json" | "image/svg+xml");
+        if !compressible {
+            return None;
+        }
+        let mut cache = COMPRESSED.get_or_init(Default::default).lock().unwrap();
+        if let Some(data) = cache.get(&(name, encoding)) {
+            return Some(data.clone());
+        }
+        let data = Arc::new(encoding.compress(&self.data).ok()?);
+        cache.insert((name, encoding), data.clone());
+        Some(data)
+    }
+}
+
+pub fn mime_type(path: &Path) -> &'static str {
+    let ext = path.extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_ascii_lowercase());
+    match ext.as_deref() {
+        Some("html" | "htm") => "text/html",
+        Some("js" | "mjs") => "text/javascript",
+        Some("css") => "text/css",
+        Some("json") => "application/json",
+        Some("webmanifest") => "application/manifest+json",
+        Some("txt") => "text/plain",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("ico") => "image/x-icon",
+        Some("woff2") => "font/woff2",
+        Some("woff") => "font/woff",
+        Some("ttf") => "font/ttf",
+        _ => "application/octet-stream",
+    }
+}
+
+/// modification time of the embedded files, when the plugin was built
+fn build_time() -> SystemTime {
+    let secs = env!("MPV_REMOTE_BUILD_TIME").parse().unwrap_or(0);
+    SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+}
+
+/// Joins url path components onto `root`, refusing anything that could leave it.
+fn join_relative(root: &Path, path: &str) -> Option<PathBuf> {
+    let mut joined = root.to_path_buf();
+    for component in Path::new(path).components() {
+        match component {
+            Component::Normal(c) => joined.push(c),
+            _ => return None,
+        }
+    }
+    Some(joined)
+}
+
+/// Reads `path` and its modification time from the configured web root, the result is None if it is not there or resolves
+/// outside of the web root through a symlink.
+async fn from_web_root(web_root: &Path, path: &str) -> Option<(Vec<u8>, Option<SystemTime>)> {
+    let root = fs::canonicalize(web_root).await.ok()?;
+    let file = fs::canonicalize(join_relative(&root, path)?).await.ok()?;
+    if !file.starts_with(&root) {
+        warning!("refusing to serve {file:?} from outside of the web root");
+        return None;
+    }
+    let modified = fs::metadata(&file).await.ok()?.modified().ok();
+    Some((fs::read(file).await.ok()?, modified))
+}
+
+/// Looks up a web asset by its path relative to the web root. Files in the configured `web_root`
+/// take precedence over the embedded ones.
+pub async fn get(config: &Config, path: &str) -> Option<Asset> {
+    let mime = mime_type(Path::new(path));
+    if let Some(web_root) = &config.web_root {
+        if let Some((data, modified)) = from_web_root(web_root, path).await {
+            return Some(Asset::new(Cow::Owned(data), mime, modified, None));
+        }
+    }
+    EMBEDDED.iter()
+        .find(|(name, _)| *name == path)
+        .map(|(name, data)| Asset::new(Cow::Borrowed(*data), mime, Some(build_time()), Some(*name)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiate_encoding() {
+        assert_eq!(Encoding::negotiate("gzip, deflate, br"), Some(Encoding::Brotli));
+        assert_eq!(Encoding::negotiate("gzip, br;q=0"), Some(Encoding::Gzip));
+        assert_eq!(Encoding::negotiate("identity"), None);
+    }
+
+    #[test]
+    fn web_root_escape() {
+        assert!(join_relative(Path::new("/www"), "static/../../etc/passwd").is_none());
+        assert!(join_relative(Path::new("/www"), "/etc/passwd").is_none());
+        assert_eq!(join_relative(Path::new("/www"), "static/a.css"), Some(PathBuf::from("/www/static/a.css")));
+    }
+
+    #[tokio::test]
+    async fn embedded_last_modified() {
+        let asset = get(&Config::default(), "static/main.js").await.unwrap();
+        assert_eq!(asset.modified, Some(build_time()));
+        let since = httpdate::fmt_http_date(build_time());
+        let raw = format!("GET /static/main.js HTTP/1.1\r\nIf-Modified-Since: {since}\r\n\r\n");
+        let request = Request::parse(&mut tokio::io::BufReader::new(raw.as_bytes())).await.unwrap();
+        assert!(asset.not_modified(&request));
+        let earlier = httpdate::fmt_http_date(build_time() - Duration::from_secs(60));
+        let raw = format!("GET /static/main.js HTTP/1.1\r\nIf-Modified-Since: {earlier}\r\n\r\n");
+        let request = Request::parse(&mut tokio::io::BufReader::new(raw.as_bytes())).await.unwrap();
+        assert!(!asset.not_modified(&request));
+    }
+}
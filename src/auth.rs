@@ -0,0 +1,202 @@
+use std::net::IpAddr;
+use std::sync::OnceLock;
+use base64::Engine;
+use tokio::io::AsyncWrite;
+use anyhow::Result;
+use crate::config::Config;
+use crate::limits;
+use crate::logger::warning;
+use crate::server::{Request, Response};
+
+pub const SESSION_COOKIE: &str = "mpv-remote-session";
+
+// random per process, so restarting mpv logs every client out
+static SESSION: OnceLock<String> = OnceLock::new();
+
+fn session_token() -> &'static str {
+    SESSION.get_or_init(|| {
+        base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(rand::random::<[u8; 32]>())
+    })
+}
+
+/// compare without returning early so the time taken does not leak how much of the secret matched
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn bearer(request: &Request) -> Option<&str> {
+    request.header("Authorization")?.strip_prefix("Bearer ")
+}
+
+fn cookie<'a>(request: &'a Request, name: &str) -> Option<&'a str> {
+    request.header("Cookie")?
+        .split(';')
+        .filter_map(|c| c.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, val)| val)
+}
+
+/// Whether a request may use the remote.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    Granted,
+    Denied,
+    /// the client sent too many wrong passwords, it is refused without checking another one
+    Limited,
+}
+
+/// Checks a password sent as a bearer token. Wrong passwords are counted against the client's
+/// address, so they cannot be guessed quickly through any route.
+fn check_password(password: &str, token: &str, peer: Option<IpAddr>) -> Access {
+    if !limits::login_allowed(peer) {
+        warning!("too many wrong passwords from {peer:?}");
+        return Access::Limited;
+    }
+    if constant_time_eq(token.as_bytes(), password.as_bytes()) {
+        Access::Granted
+    } else {
+        limits::login_failed(peer);
+        Access::Denied
+    }
+}
+
+/// Grants access if no password is configured, the request carries the password as a bearer token,
+/// or it carries the session cookie handed out by `POST /login`.
+pub fn check(config: &Config, request: &Request) -> Access {
+    let Some(password) = &config.password else { return Access::Granted };
+    if let Some(token) = bearer(request) {
+        match check_password(password, token, request.peer) {
+            Access::Denied => (),
+            access => return access,
+        }
+    }
+    match cookie(request, SESSION_COOKIE) {
+        Some(session) if constant_time_eq(session.as_bytes(), session_token().as_bytes()) => Access::Granted,
+        _ => Access::Denied,
+    }
+}
+
+/// the Set-Cookie value for the session, only sent over TLS when the remote uses it
+fn session_cookie(config: &Config) -> String {
+    let secure = if config.tls { "; Secure" } else { "" };
+    format!("{SESSION_COOKIE}={}; Path=/; HttpOnly; SameSite=Strict{secure}", session_token())
+}
+
+/// the answer to a client that sent too many wrong passwords
+pub fn too_many_attempts<'a>() -> Response<'a> {
+    Response::new("HTTP/1.1", 429).header("Retry-After", "10")
+}
+
+/// Answers `POST /login`: 204 with the session cookie for the right bearer password, 401 for a wrong
+/// one and 429 once the client has sent too many wrong ones.
+pub async fn handle_login<T>(request: &Request, stream: &mut T, config: &Config) -> Result<()>
+where
+    T: AsyncWrite + Unpin,
+{
+    let access = match (&config.password, bearer(request)) {
+        (Some(password), Some(token)) => check_password(password, token, request.peer),
+        _ => Access::Denied,
+    };
+    let response = match access {
+        Access::Granted => Response::new("HTTP/1.1", 204).header("Set-Cookie", &session_cookie(config)),
+        Access::Denied => Response::new("HTTP/1.1", 401),
+        Access::Limited => too_many_attempts(),
+    };
+    response.write_to(stream).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::BufReader;
+
+    async fn request(headers: &str, peer: &str) -> Request {
+        let raw = format!("POST /login HTTP/1.1\r\n{headers}\r\n");
+        let mut request = Request::parse(&mut BufReader::new(raw.as_bytes())).await.unwrap();
+        request.peer = Some(peer.parse().unwrap());
+        request
+    }
+
+    fn config(password: Option<&str>) -> Config {
+        Config { password: password.map(Into::into), ..Config::default() }
+    }
+
+    async fn login_response(request: &Request, config: &Config) -> String {
+        let mut out = Vec::new();
+        handle_login(request, &mut out, config).await.unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[tokio::test]
+    async fn credentials() {
+        let anonymous = request("", "192.0.2.1").await;
+        assert_eq!(check(&config(None), &anonymous), Access::Granted);
+        let config = config(Some("hunter2"));
+        assert_eq!(check(&config, &anonymous), Access::Denied);
+        assert_eq!(check(&config, &request("Authorization: Bearer hunter2\r\n", "192.0.2.1").await), Access::Granted);
+        assert_eq!(check(&config, &request("Authorization: Bearer hunter3\r\n", "192.0.2.1").await), Access::Denied);
+        assert_eq!(check(&config, &request("Authorization: Basic hunter2\r\n", "192.0.2.1").await), Access::Denied);
+
+        let session = format!("Cookie: theme=dark; {SESSION_COOKIE}={}\r\n", session_token());
+        assert_eq!(check(&config, &request(&session, "192.0.2.1").await), Access::Granted);
+        let mut tampered = session_token().to_string();
+        tampered.replace_range(.. 1, if tampered.starts_with('A') { "B" } else { "A" });
+        let tampered = format!("Cookie: {SESSION_COOKIE}={tampered}\r\n");
+        assert_eq!(check(&config, &request(&tampered, "192.0.2.1").await), Access::Denied);
+    }
+
+    #[tokio::test]
+    async fn bearer_guessing() {
+        // wrong passwords on any route count, after a few even the right one is refused
+        let config = config(Some("hunter2"));
+        let guess = request("Authorization: Bearer guess\r\n", "192.0.2.20").await;
+        for _ in 0 .. 5 {
+            assert_eq!(check(&config, &guess), Access::Denied);
+        }
+        assert_eq!(check(&config, &guess), Access::Limited);
+        assert_eq!(check(&config, &request("Authorization: Bearer hunter2\r\n", "192.0.2.20").await), Access::Limited);
+        // right passwords and session cookies are not counted
+        let right = request("Authorization: Bearer hunter2\r\n", "192.0.2.21").await;
+        for _ in 0 .. 10 {
+            assert_eq!(check(&config, &right), Access::Granted);
+        }
+    }
+
+    #[tokio::test]
+    async fn login_route() {
+        let config = config(Some("hunter2"));
+        let wrong = login_response(&request("Authorization: Bearer hunter3\r\n", "192.0.2.10").await, &config).await;
+        assert!(wrong.starts_with("HTTP/1.1 401 "));
+        assert!(!wrong.contains("Set-Cookie"));
+        let right = login_response(&request("Authorization: Bearer hunter2\r\n", "192.0.2.10").await, &config).await;
+        assert!(right.starts_with("HTTP/1.1 204 "));
+        let cookie = right.lines().find_map(|line| line.strip_prefix("Set-Cookie: ")).unwrap();
+        assert!(cookie.starts_with(&format!("{SESSION_COOKIE}={}", session_token())));
+        assert!(cookie.ends_with("; HttpOnly; SameSite=Strict"));
+        let tls = Config { tls: true, password: Some("hunter2".into()), ..Config::default() };
+        let secure = login_response(&request("Authorization: Bearer hunter2\r\n", "192.0.2.10").await, &tls).await;
+        assert!(secure.contains("; HttpOnly; SameSite=Strict; Secure\r\n"));
+
+        // guessing is cut off after a few attempts, other addresses are unaffected
+        let guess = request("Authorization: Bearer guess\r\n", "192.0.2.11").await;
+        for _ in 0 .. 5 {
+            assert!(login_response(&guess, &config).await.starts_with("HTTP/1.1 401 "));
+        }
+        let limited = login_response(&guess, &config).await;
+        assert!(limited.starts_with("HTTP/1.1 429 "));
+        assert!(limited.contains("\r\nRetry-After: 10\r\n"));
+        let other = login_response(&request("Authorization: Bearer hunter2\r\n", "192.0.2.12").await, &config).await;
+        assert!(other.starts_with("HTTP/1.1 204 "));
+    }
+
+    #[test]
+    fn compare() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secrets"));
+        assert!(constant_time_eq(b"", b""));
+    }
+}
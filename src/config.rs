@@ -1,24 +1,203 @@
 use serde_json;
 use serde::Deserialize;
 use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
 use dirs;
 
+/// directory the file picker may browse, shown under `name`
+#[derive(Deserialize, Clone)]
+pub struct MediaRoot {
+    pub name: String,
+    pub path: PathBuf,
+}
+
+/// address the server listens on, written in the config as one of
+/// - `"192.168.1.2:5585"` or `"[::1]:5585"`: a single address, IPv6 addresses only accept IPv6 clients
+/// - `"*:5585"`: every interface, IPv4 and IPv6
+/// - `"localhost:5585"`: every address the host name resolves to
+/// - `"unix:/run/user/1000/mpv-remote.sock"`: a unix domain socket
+#[derive(Debug, Clone, PartialEq)]
+pub enum Endpoint {
+    Tcp(SocketAddr),
+    DualStack(u16),
+    Host(String, u16),
+    Unix(PathBuf),
+}
+
+impl std::fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Endpoint::Tcp(addr) => write!(f, "{addr}"),
+            Endpoint::DualStack(port) => write!(f, "*:{port}"),
+            Endpoint::Host(host, port) => write!(f, "{host}:{port}"),
+            Endpoint::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl FromStr for Endpoint {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            return Ok(Endpoint::Unix(path.into()));
+        }
+        if let Ok(addr) = s.parse() {
+            return Ok(Endpoint::Tcp(addr));
+        }
+        let (host, port) = s.rsplit_once(':').ok_or(format!("listen address \"{s}\" has no port"))?;
+        let port = port.parse().map_err(|_| format!("invalid port in listen address \"{s}\""))?;
+        match host {
+            "*" => Ok(Endpoint::DualStack(port)),
+            "" => Err(format!("listen address \"{s}\" has no host")),
+            host => Ok(Endpoint::Host(host.into(), port)),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Endpoint {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(serde::de::Error::custom)
+    }
+}
+
 #[derive(Deserialize)]
+#[serde(default)]
 pub struct Config {
     pub host: String,
     pub port: u16,
+    /// addresses to listen on, `host` and `port` are used when empty
+    pub listen: Vec<Endpoint>,
+    /// shared secret required by every client, the server is open to anyone when unset
+    pub password: Option<String>,
+    /// serve HTTPS and WSS instead of plaintext
+    pub tls: bool,
+    /// PEM certificate chain, a self-signed certificate is generated when unset
+    pub tls_cert: Option<PathBuf>,
+    /// PEM private key matching `tls_cert`
+    pub tls_key: Option<PathBuf>,
+    /// largest request body accepted, in bytes
+    pub max_body_size: u64,
+    /// seconds an idle keep-alive connection is held open
+    pub idle_timeout: u64,
+    /// origins of other web pages allowed to call the JSON routes, `*` for any
+    pub cors_origins: Vec<String>,
+    /// open connections the server accepts at once, 0 for no limit
+    pub max_connections: usize,
+    /// open connections a single client address may have, 0 for no limit
+    pub max_connections_per_ip: usize,
+    /// commands a client may send per second on average, 0 for no limit
+    pub commands_per_second: f64,
+    /// commands a client may send in a burst before `commands_per_second` applies
+    pub command_burst: u32,
+    /// directory whose files are served in place of the embedded web UI
+    pub web_root: Option<PathBuf>,
+    /// directories the file picker can browse and play from, mpv's working directory when empty
+    pub media_roots: Vec<MediaRoot>,
+    /// allow symlinks inside the media roots that point outside of them
+    pub follow_symlinks: bool,
+    /// URL schemes clients may have mpv load, compared case-insensitively
+    pub url_schemes: Vec<String>,
+    /// directory uploaded files are stored in, uploads are disabled when unset
+    pub upload_dir: Option<PathBuf>,
+    /// largest file accepted by the upload endpoint, in bytes
+    pub max_upload_size: u64,
+    /// directory uploaded subtitles are stored in, see `Config::subtitle_dir`
+    pub subtitle_dir: Option<PathBuf>,
+    /// name the remote is shown under, "mpvRemote on <hostname>" when unset
+    pub name: Option<String>,
+    /// announce the remote on the local network over multicast DNS
+    pub mdns: bool,
+    /// colour of the installed app's title bar and icon, as `#rrggbb`
+    pub theme_color: String,
 }
 
-impl Config {
-    pub fn default() -> Self {
+impl Default for Config {
+    fn default() -> Self {
         Config {
             host: "0.0.0.0".into(),
             port: 5585,
+            listen: Vec::new(),
+            password: None,
+            tls: false,
+            tls_cert: None,
+            tls_key: None,
+            max_body_size: 1024 * 1024,
+            idle_timeout: 15,
+            cors_origins: Vec::new(),
+            max_connections: 256,
+            max_connections_per_ip: 32,
+            commands_per_second: 10.0,
+            command_burst: 30,
+            web_root: None,
+            media_roots: Vec::new(),
+            follow_symlinks: false,
+            url_schemes: [
+                "http", "https", "ftp", "ftps", "sftp", "smb", "rtsp", "rtsps", "rtmp", "rtmps", "rtp", "srt", "udp",
+                "mms", "mmsh", "mmst", "ytdl",
+            ].map(String::from).to_vec(),
+            upload_dir: None,
+            max_upload_size: 4 * 1024 * 1024 * 1024,
+            subtitle_dir: None,
+            name: None,
+            mdns: true,
+            theme_color: "#1f2937".into(),
         }
     }
-    pub fn load() -> Result<Self, io::Error> { 
+}
+
+impl Config {
+    /// mpv's script-opts directory, where the config and any generated files live
+    pub fn dir() -> Result<PathBuf, io::Error> {
         let conf_dir = dirs::config_dir().ok_or(io::Error::new(io::ErrorKind::Other, "unable to locate config directory"))?;
-        let conf = conf_dir.join("mpv/script-opts/mpv-remote.json");
+        Ok(conf_dir.join("mpv/script-opts"))
+    }
+    /// the configured listen addresses, or `host` and `port` when there are none
+    pub fn endpoints(&self) -> Vec<Endpoint> {
+        if !self.listen.is_empty() {
+            return self.listen.clone();
+        }
+        match self.host.parse() {
+            Ok(ip) => vec![Endpoint::Tcp(SocketAddr::new(ip, self.port))],
+            Err(_) => vec![Endpoint::Host(self.host.clone(), self.port)],
+        }
+    }
+    /// where uploaded subtitles go: `subtitle_dir`, else a `subtitles` directory in the upload directory,
+    /// else the user's cache directory, so subtitles can be added with uploads disabled
+    pub fn subtitle_dir(&self) -> Option<PathBuf> {
+        if let Some(dir) = &self.subtitle_dir {
+            return Some(dir.clone());
+        }
+        match &self.upload_dir {
+            Some(dir) => Some(dir.join("subtitles")),
+            None => Some(dirs::cache_dir()?.join("mpv-remote/subtitles")),
+        }
+    }
+    pub fn instance_name(&self) -> String {
+        match &self.name {
+            Some(name) => name.clone(),
+            None => format!("mpvRemote on {}", gethostname::gethostname().to_string_lossy()),
+        }
+    }
+    pub fn load() -> Result<Self, io::Error> { 
+        let conf = Self::dir()?.join("mpv-remote.json");
         serde_json::from_str(&std::fs::read_to_string(conf)?).map_err(|e| {io::Error::new(io::ErrorKind::Other, e)})
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn endpoints() {
+        assert_eq!("127.0.0.1:80".parse(), Ok(Endpoint::Tcp("127.0.0.1:80".parse().unwrap())));
+        assert_eq!("[::1]:80".parse(), Ok(Endpoint::Tcp("[::1]:80".parse().unwrap())));
+        assert_eq!("*:5585".parse(), Ok(Endpoint::DualStack(5585)));
+        assert_eq!("localhost:5585".parse(), Ok(Endpoint::Host("localhost".into(), 5585)));
+        assert_eq!("unix:/tmp/mpv.sock".parse(), Ok(Endpoint::Unix("/tmp/mpv.sock".into())));
+        assert!("localhost".parse::<Endpoint>().is_err());
+        assert!(":80".parse::<Endpoint>().is_err());
+    }
+}
@@ -0,0 +1,80 @@
+use tokio::io::AsyncWrite;
+use anyhow::Result;
+use crate::config::Config;
+use crate::server::{Request, Response};
+
+/// methods used by the JSON routes
+const METHODS: &str = "GET, POST, PUT, DELETE";
+/// seconds browsers may cache a preflight answer
+const MAX_AGE: &str = "600";
+
+/// The CORS headers for a request from another origin, empty when the request is same-origin or its
+/// origin is not in `cors_origins`. Listed origins may send cookies, a `*` entry lets any other
+/// origin in without them.
+pub fn headers(config: &Config, request: &Request) -> Vec<(String, String)> {
+    let Some(origin) = request.header("Origin") else { return Vec::new() };
+    let origin = origin.trim_end_matches('/');
+    let listed = config.cors_origins.iter().any(|allowed| allowed.trim_end_matches('/').eq_ignore_ascii_case(origin));
+    let mut headers = Vec::new();
+    if listed {
+        headers.push(("Access-Control-Allow-Origin".into(), origin.into()));
+        headers.push(("Access-Control-Allow-Credentials".into(), "true".into()));
+    } else if config.cors_origins.iter().any(|allowed| allowed == "*") {
+        headers.push(("Access-Control-Allow-Origin".into(), "*".into()));
+    } else {
+        return headers;
+    }
+    headers.push(("Access-Control-Expose-Headers".into(), "Retry-After".into()));
+    headers.push(("Vary".into(), "Origin".into()));
+    headers
+}
+
+/// Answers an `OPTIONS` request to a JSON route. Preflights from allowed origins may use any of the
+/// routes' methods and whatever headers they ask for, others are refused with 403.
+pub async fn preflight<T>(request: &Request, stream: &mut T) -> Result<()>
+where
+    T: AsyncWrite + Unpin,
+{
+    let requested = request.header("Access-Control-Request-Method");
+    let response = if requested.is_some() && request.response_headers.is_empty() {
+        Response::new("HTTP/1.1", 403)
+    } else {
+        let mut response = Response::new("HTTP/1.1", 204)
+            .header("Allow", &format!("{METHODS}, OPTIONS"))
+            .headers(&request.response_headers);
+        if requested.is_some() {
+            response = response
+                .header("Access-Control-Allow-Methods", METHODS)
+                .header("Access-Control-Max-Age", MAX_AGE);
+            if let Some(headers) = request.header("Access-Control-Request-Headers") {
+                response = response.header("Access-Control-Allow-Headers", headers);
+            }
+        }
+        response
+    };
+    response.write_to(stream).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::BufReader;
+
+    async fn request(origin: &str) -> Request {
+        let raw = format!("OPTIONS /api/seek HTTP/1.1\r\nOrigin: {origin}\r\nAccess-Control-Request-Method: POST\r\n\r\n");
+        Request::parse(&mut BufReader::new(raw.as_bytes())).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn allowlist() {
+        let config = Config { cors_origins: vec!["https://dash.example".into()], ..Config::default() };
+        let allowed = headers(&config, &request("https://dash.example").await);
+        assert!(allowed.contains(&("Access-Control-Allow-Origin".into(), "https://dash.example".into())));
+        assert!(headers(&config, &request("https://evil.example").await).is_empty());
+        let config = Config { cors_origins: vec!["*".into()], ..Config::default() };
+        let any = headers(&config, &request("https://evil.example").await);
+        assert!(any.contains(&("Access-Control-Allow-Origin".into(), "*".into())));
+        assert!(!any.iter().any(|(key, _)| key == "Access-Control-Allow-Credentials"));
+    }
+}
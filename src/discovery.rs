@@ -0,0 +1,107 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+use mdns_sd::{ServiceDaemon, ServiceInfo};
+use anyhow::{Result, anyhow};
+use crate::config::Config;
+use crate::logger::{info, warning};
+
+/// custom service type, so apps can find the remote among other web servers
+const REMOTE_SERVICE: &str = "_mpv-remote._tcp.local.";
+/// how long to wait for the daemon to send its goodbye packets on shutdown
+const WITHDRAW_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The services announced over multicast DNS, kept announced until `withdraw` is called.
+pub struct Advertisement {
+    daemon: ServiceDaemon,
+    services: Vec<String>,
+}
+
+/// Announces the remote as a web server (`_http._tcp`, or `_https._tcp` with TLS) and as
+/// `_mpv-remote._tcp`, on the port of the first of the bound `listeners` reachable from the network.
+pub fn advertise(config: &Config, listeners: &[SocketAddr]) -> Result<Advertisement> {
+    let host_name = format!("{}.local.", gethostname::gethostname().to_string_lossy());
+    let infos = service_info(config, &host_name, listeners)?;
+    let daemon = ServiceDaemon::new()?;
+    let port = infos.first().map_or(0, |service| service.get_port());
+    let mut services = Vec::new();
+    for service in infos {
+        services.push(service.get_fullname().to_string());
+        daemon.register(service)?;
+    }
+    info!("announcing \"{}\" on port {port} over mdns", config.instance_name());
+    Ok(Advertisement { daemon, services })
+}
+
+/// The services announced for `host_name`. The TXT records carry the instance name, plugin version
+/// and UI path. Loopback listeners are left out, they cannot be reached by other devices.
+fn service_info(config: &Config, host_name: &str, listeners: &[SocketAddr]) -> Result<Vec<ServiceInfo>> {
+    let name = config.instance_name();
+    let listeners: Vec<&SocketAddr> = listeners.iter().filter(|addr| !addr.ip().is_loopback()).collect();
+    let port = listeners.first().map(|addr| addr.port()).ok_or(anyhow!("no tcp listener reachable from the network"))?;
+    // the addresses of listeners bound to one address, a wildcard listener is announced on all of the
+    // host's addresses
+    let mut addrs = Vec::new();
+    if listeners.iter().all(|addr| !addr.ip().is_unspecified()) {
+        addrs.extend(listeners.iter().map(|addr| addr.ip()));
+    }
+    let properties = [
+        ("name", name.as_str()),
+        ("version", env!("CARGO_PKG_VERSION")),
+        ("path", "/"),
+    ];
+    let web_service = if config.tls { "_https._tcp.local." } else { "_http._tcp.local." };
+    [web_service, REMOTE_SERVICE].into_iter().map(|ty| {
+        let service = ServiceInfo::new(ty, &name, host_name, &addrs[..], port, &properties[..])?;
+        Ok(if addrs.is_empty() { service.enable_addr_auto() } else { service })
+    }).collect()
+}
+
+impl Advertisement {
+    /// Unregisters every service, which sends goodbye packets so clients forget the remote right
+    /// away, and stops the daemon.
+    pub fn withdraw(self) {
+        for service in &self.services {
+            match self.daemon.unregister(service) {
+                Ok(status) => { let _ = status.recv_timeout(WITHDRAW_TIMEOUT); },
+                Err(e) => warning!("withdrawing {service}: {e}"),
+            }
+        }
+        if let Ok(status) = self.daemon.shutdown() {
+            let _ = status.recv_timeout(WITHDRAW_TIMEOUT);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+    use super::*;
+
+    #[test]
+    fn announced_services() {
+        let config = Config { tls: true, ..Config::default() };
+        let listeners = ["127.0.0.1:8000".parse().unwrap(), "0.0.0.0:5585".parse().unwrap()];
+        let services = service_info(&config, "media-pc.local.", &listeners).unwrap();
+        let types: Vec<&str> = services.iter().map(|s| s.get_type()).collect();
+        assert_eq!(types, ["_https._tcp.local.", REMOTE_SERVICE]);
+        let name = config.instance_name();
+        for service in &services {
+            assert_eq!(service.get_fullname(), format!("{name}.{}", service.get_type()));
+            assert_eq!(service.get_hostname(), "media-pc.local.");
+            assert_eq!(service.get_port(), 5585);
+            assert_eq!(service.get_property_val_str("name"), Some(name.as_str()));
+            assert_eq!(service.get_property_val_str("version"), Some(env!("CARGO_PKG_VERSION")));
+            assert_eq!(service.get_property_val_str("path"), Some("/"));
+        }
+        let plain = service_info(&Config::default(), "media-pc.local.", &listeners).unwrap();
+        assert_eq!(plain[0].get_type(), "_http._tcp.local.");
+        // a listener bound to one address is announced on that address only
+        let lan = ["192.168.1.20:5585".parse().unwrap()];
+        let services = service_info(&config, "media-pc.local.", &lan).unwrap();
+        let addrs: Vec<IpAddr> = services[0].get_addresses().iter().copied().collect();
+        assert_eq!(addrs, ["192.168.1.20".parse::<IpAddr>().unwrap()]);
+        // loopback-only listeners are not announced
+        let local = ["127.0.0.1:5585".parse().unwrap(), "[::1]:5585".parse().unwrap()];
+        assert!(service_info(&config, "media-pc.local.", &local).is_err());
+    }
+}
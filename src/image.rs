@@ -0,0 +1,168 @@
+use anyhow::{Result, bail};
+
+/// 8 bit RGB pixels, row by row
+#[derive(Debug)]
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub rgb: Vec<u8>,
+}
+
+impl Image {
+    /// Converts the packed pixels returned by mpv's `screenshot-raw`, whose `format` is one of
+    /// `bgr0`, `bgra` or `rgba`, each row being `stride` bytes long.
+    pub fn from_raw(width: usize, height: usize, stride: usize, format: &str, data: &[u8]) -> Result<Self> {
+        let (r, g, b) = match format {
+            "bgr0" | "bgra" => (2, 1, 0),
+            "rgba" => (0, 1, 2),
+            format => bail!("unsupported screenshot format \"{format}\""),
+        };
+        if width == 0 || height == 0 {
+            bail!("empty {width}x{height} screenshot");
+        }
+        if stride < width * 4 || data.len() < stride * height.saturating_sub(1) + width * 4 {
+            bail!("screenshot data too short for a {width}x{height} image");
+        }
+        let mut rgb = Vec::with_capacity(width * height * 3);
+        for row in data.chunks(stride).take(height) {
+            for px in row[.. width * 4].chunks_exact(4) {
+                rgb.extend_from_slice(&[px[r], px[g], px[b]]);
+            }
+        }
+        Ok(Image { width, height, rgb })
+    }
+
+    /// Size that fits within `max_width` x `max_height` keeping the aspect ratio, images are never
+    /// enlarged.
+    pub fn fit(&self, max_width: Option<usize>, max_height: Option<usize>) -> (usize, usize) {
+        let scale_w = max_width.map_or(1.0, |w| w as f64 / self.width as f64);
+        let scale_h = max_height.map_or(1.0, |h| h as f64 / self.height as f64);
+        let scale = scale_w.min(scale_h).min(1.0);
+        let width = ((self.width as f64 * scale).round() as usize).max(1);
+        let height = ((self.height as f64 * scale).round() as usize).max(1);
+        (width, height)
+    }
+
+    /// Shrinks the image by averaging the block of source pixels behind each new pixel.
+    pub fn resize(&self, width: usize, height: usize) -> Image {
+        if width >= self.width && height >= self.height {
+            return Image { width: self.width, height: self.height, rgb: self.rgb.clone() };
+        }
+        let mut rgb = Vec::with_capacity(width * height * 3);
+        for y in 0 .. height {
+            let y0 = y * self.height / height;
+            let y1 = ((y + 1) * self.height / height).max(y0 + 1);
+            for x in 0 .. width {
+                let x0 = x * self.width / width;
+                let x1 = ((x + 1) * self.width / width).max(x0 + 1);
+                let mut sum = [0u32; 3];
+                for sy in y0 .. y1 {
+                    let row = &self.rgb[sy * self.width * 3 ..];
+                    for sx in x0 .. x1 {
+                        for (total, &value) in sum.iter_mut().zip(&row[sx * 3 .. sx * 3 + 3]) {
+                            *total += value as u32;
+                        }
+                    }
+                }
+                let count = ((y1 - y0) * (x1 - x0)) as u32;
+                rgb.extend(sum.iter().map(|s| ((s + count / 2) / count) as u8));
+            }
+        }
+        Image { width, height, rgb }
+    }
+
+    pub fn png(&self) -> Result<Vec<u8>> {
+        self.check_size()?;
+        let mut out = Vec::new();
+        let mut encoder = png::Encoder::new(&mut out, self.width as u32, self.height as u32);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.set_compression(png::Compression::Fast);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&self.rgb)?;
+        writer.finish()?;
+        Ok(out)
+    }
+
+    /// Encodes a baseline JPEG without chroma subsampling, `quality` going from 1 to 100.
+    pub fn jpeg(&self, quality: u8) -> Result<Vec<u8>> {
+        self.check_size()?;
+        let (Ok(width), Ok(height)) = (u16::try_from(self.width), u16::try_from(self.height)) else {
+            bail!("{}x{} is too large for a jpeg", self.width, self.height);
+        };
+        let mut out = Vec::new();
+        let mut encoder = jpeg_encoder::Encoder::new(&mut out, quality.clamp(1, 100));
+        encoder.set_sampling_factor(jpeg_encoder::SamplingFactor::R_4_4_4);
+        encoder.encode(&self.rgb, width, height, jpeg_encoder::ColorType::Rgb)?;
+        Ok(out)
+    }
+
+    fn check_size(&self) -> Result<()> {
+        if self.width == 0 || self.height == 0 {
+            bail!("cannot encode an empty {}x{} image", self.width, self.height);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gradient(width: usize, height: usize) -> Image {
+        let rgb = (0 .. width * height).flat_map(|i| [(i % width) as u8, (i / width) as u8, 128]).collect();
+        Image { width, height, rgb }
+    }
+
+    #[test]
+    fn fit_and_resize() {
+        let image = gradient(64, 32);
+        assert_eq!(image.fit(Some(16), None), (16, 8));
+        assert_eq!(image.fit(Some(16), Some(2)), (4, 2));
+        assert_eq!(image.fit(Some(1000), None), (64, 32));
+        let small = image.resize(16, 8);
+        assert_eq!(small.rgb.len(), 16 * 8 * 3);
+        // average of source columns 4 to 7
+        assert_eq!(small.rgb[3], 6);
+    }
+
+    #[test]
+    fn encoders() {
+        let image = gradient(21, 13);
+        let png = image.png().unwrap();
+        assert!(png.starts_with(b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR\0\0\0\x15\0\0\0\x0d"));
+        let jpeg = image.jpeg(85).unwrap();
+        assert!(jpeg.starts_with(&[0xff, 0xd8, 0xff]));
+        assert!(jpeg.ends_with(&[0xff, 0xd9]));
+    }
+
+    #[test]
+    fn round_trip() {
+        let image = gradient(21, 13);
+
+        let png = image.png().unwrap();
+        let mut reader = png::Decoder::new(&png[..]).read_info().unwrap();
+        let mut rgb = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut rgb).unwrap();
+        assert_eq!((info.width, info.height, info.color_type), (21, 13, png::ColorType::Rgb));
+        assert_eq!(rgb, image.rgb);
+
+        let jpeg = image.jpeg(95).unwrap();
+        let mut decoder = jpeg_decoder::Decoder::new(&jpeg[..]);
+        let rgb = decoder.decode().unwrap();
+        let info = decoder.info().unwrap();
+        assert_eq!((info.width, info.height), (21, 13));
+        assert_eq!(rgb.len(), image.rgb.len());
+        // lossy, but every channel stays close to the original
+        assert!(rgb.iter().zip(&image.rgb).all(|(a, b)| a.abs_diff(*b) <= 8));
+    }
+
+    #[test]
+    fn empty_frames() {
+        assert!(Image::from_raw(0, 10, 0, "bgr0", &[]).is_err());
+        assert!(Image::from_raw(10, 0, 40, "bgr0", &[]).is_err());
+        let empty = Image { width: 0, height: 0, rgb: Vec::new() };
+        assert!(empty.png().is_err());
+        assert!(empty.jpeg(90).is_err());
+    }
+}
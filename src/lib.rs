@@ -1,13 +1,48 @@
 mod server;
+mod assets;
+mod api;
+mod upload;
+mod screenshot;
+mod artwork;
+mod sse;
+mod stream;
+mod auth;
+mod cors;
+mod tls;
 mod websocket;
 mod mpv;
 mod plugin;
+mod pwa;
+mod playlist;
 mod config;
+mod discovery;
+mod media;
+mod image;
 mod logger;
+mod metrics;
+mod limits;
+#[cfg(test)]
+mod testing;
 
 use mpv_client::{mpv_handle, Event, Handle};
 use mpv::{EventBroadcaster, SplitHandle};
 use tokio::runtime::Runtime;
+use tokio::task::JoinHandle;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// how long connections get to close once mpv shuts down
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Waits up to `timeout` for the server to finish closing its connections, then drops whatever is
+/// left on the runtime.
+fn stop_server(rt: Runtime, server: JoinHandle<()>, timeout: Duration) {
+    // the timer needs the runtime's reactor, so it is created inside it
+    if rt.block_on(async { tokio::time::timeout(timeout, server).await }).is_err() {
+        logger::warning!("connections still open after {timeout:?}, closing them");
+    }
+    rt.shutdown_timeout(Duration::from_millis(500));
+}
 
 #[no_mangle]
 extern "C" fn mpv_open_cplugin(handle: *mut mpv_handle) -> std::os::raw::c_int {
@@ -19,6 +54,7 @@ extern "C" fn mpv_open_cplugin(handle: *mut mpv_handle) -> std::os::raw::c_int {
             config::Config::default()
         },
     };
+    let config = Arc::new(config);
     let handle = Handle::from_ptr(handle);
     let (mut event_handle, mut cmd_handle) = SplitHandle(handle);
 
@@ -27,9 +63,11 @@ extern "C" fn mpv_open_cplugin(handle: *mut mpv_handle) -> std::os::raw::c_int {
     mpv::ObservedPropID::observe_all(&mut cmd_handle).unwrap();
 
     let rt = Runtime::new().unwrap();
+    let artwork_handle = cmd_handle.clone();
     // webserver
-    rt.spawn(async move {
-        match server::bind_and_listen((config.host, config.port), cmd_handle, subscriber).await {
+    let server_config = config.clone();
+    let server = rt.spawn(async move {
+        match server::bind_and_listen(server_config, cmd_handle, subscriber).await {
         Ok(_) => (),
         Err(e) => logger::error!("failed to start server: {e}"),
         }
@@ -37,10 +75,28 @@ extern "C" fn mpv_open_cplugin(handle: *mut mpv_handle) -> std::os::raw::c_int {
     // mpv event loop
     loop {
         match event_handle.wait_event(-1.) {
-            Event::Shutdown => return 0,
+            Event::Shutdown => {
+                let _ = event_chan.send(mpv::Event::Shutdown);
+                stop_server(rt, server, SHUTDOWN_TIMEOUT);
+                return 0;
+            },
             evt => {
                 let evt = mpv::unwrap_or_continue!(mpv::Event::from_mpv_client(&evt));
                 match evt {
+                    Some(mpv::Event::FileLoaded) => {
+                        // the artwork lookup reads the file's directory, so it runs on the runtime and the
+                        // event is passed on once the status sent with it can tell
+                        let event_chan = event_chan.clone();
+                        let artwork_handle = artwork_handle.clone();
+                        rt.spawn(async move {
+                            artwork::refresh(artwork_handle).await;
+                            let _ = event_chan.send(mpv::Event::FileLoaded);
+                        });
+                    },
+                    Some(mpv::Event::EndFile) => {
+                        artwork::clear();
+                        let _ = event_chan.send(mpv::Event::EndFile);
+                    },
                     Some(e) => {
                         let _ = event_chan.send(e);
                     },
@@ -50,3 +106,26 @@ extern "C" fn mpv_open_cplugin(handle: *mut mpv_handle) -> std::os::raw::c_int {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // runs outside of any runtime, like mpv's plugin thread
+    #[test]
+    fn stop_server_outside_runtime() {
+        let rt = Runtime::new().unwrap();
+        let event_chan = EventBroadcaster::new(4);
+        let shutdown = mpv::shutdown(event_chan.subscribe());
+        let server = rt.spawn(shutdown);
+        let _ = event_chan.send(mpv::Event::Shutdown);
+        stop_server(rt, server, Duration::from_secs(2));
+
+        // a server that never finishes is given up on after the timeout
+        let rt = Runtime::new().unwrap();
+        let server = rt.spawn(std::future::pending());
+        let start = std::time::Instant::now();
+        stop_server(rt, server, Duration::from_millis(50));
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+}
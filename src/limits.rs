@@ -0,0 +1,181 @@
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+use crate::config::Config;
+
+/// buckets kept before full ones, whose clients have been quiet for a while, are dropped
+const MAX_BUCKETS: usize = 1024;
+/// wrong passwords a client may send in a row, then one every 10 seconds
+const LOGIN_BURST: f64 = 5.0;
+const LOGINS_PER_SECOND: f64 = 0.1;
+
+struct Connections {
+    total: usize,
+    per_ip: BTreeMap<IpAddr, usize>,
+}
+
+static CONNECTIONS: Mutex<Connections> = Mutex::new(Connections { total: 0, per_ip: BTreeMap::new() });
+/// command token buckets by client
+static BUCKETS: Mutex<BTreeMap<Client, Bucket>> = Mutex::new(BTreeMap::new());
+/// login attempt token buckets by client address
+static LOGIN_BUCKETS: Mutex<BTreeMap<Option<IpAddr>, Bucket>> = Mutex::new(BTreeMap::new());
+
+/// Holds one of the open connection slots, released when dropped.
+#[derive(Debug)]
+pub struct ConnectionGuard(Option<IpAddr>);
+
+/// Takes a connection slot for a client, None when the server or the client is at its limit. A limit
+/// of 0 means no limit.
+pub fn connect(config: &Config, peer: Option<IpAddr>) -> Option<ConnectionGuard> {
+    let mut connections = CONNECTIONS.lock().unwrap();
+    if config.max_connections > 0 && connections.total >= config.max_connections {
+        return None;
+    }
+    if let Some(ip) = peer {
+        let count = connections.per_ip.entry(ip).or_default();
+        if config.max_connections_per_ip > 0 && *count >= config.max_connections_per_ip {
+            return None;
+        }
+        *count += 1;
+    }
+    connections.total += 1;
+    Some(ConnectionGuard(peer))
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut connections = CONNECTIONS.lock().unwrap();
+        connections.total -= 1;
+        if let Some(ip) = self.0 {
+            if let Some(count) = connections.per_ip.get_mut(&ip) {
+                *count -= 1;
+                if *count == 0 {
+                    connections.per_ip.remove(&ip);
+                }
+            }
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Who a command bucket is kept for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Client {
+    /// one websocket connection, see `SocketBucket`
+    Socket(u64),
+    /// HTTP requests, which have no connection to tell clients apart by, so they fall back to the
+    /// client address. Every unix socket client shares the `None` bucket, and clients behind one NAT
+    /// share the bucket of its address.
+    Address(Option<IpAddr>),
+}
+
+/// The command bucket of a websocket connection, so clients sharing an address do not slow each
+/// other down. Removed when dropped.
+#[derive(Debug)]
+pub struct SocketBucket(u64);
+
+impl SocketBucket {
+    pub fn new() -> SocketBucket {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        SocketBucket(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn client(&self) -> Client {
+        Client::Socket(self.0)
+    }
+}
+
+impl Drop for SocketBucket {
+    fn drop(&mut self) {
+        BUCKETS.lock().unwrap().remove(&self.client());
+    }
+}
+
+/// Takes a token from the client's bucket, false when it is empty and the command should be
+/// rejected. Buckets hold `command_burst` tokens and refill at `commands_per_second`, a rate of 0
+/// disables the limit.
+pub fn command(config: &Config, client: Client) -> bool {
+    if config.commands_per_second <= 0.0 {
+        return true;
+    }
+    take(&BUCKETS, client, config.commands_per_second, config.command_burst.max(1) as f64, 1.0)
+}
+
+/// Whether the client may try a password, false once it has sent too many wrong ones lately.
+pub fn login_allowed(peer: Option<IpAddr>) -> bool {
+    take(&LOGIN_BUCKETS, peer, LOGINS_PER_SECOND, LOGIN_BURST, 0.0)
+}
+
+/// Counts a wrong password against the client's login bucket.
+pub fn login_failed(peer: Option<IpAddr>) {
+    take(&LOGIN_BUCKETS, peer, LOGINS_PER_SECOND, LOGIN_BURST, 1.0);
+}
+
+/// Takes `cost` tokens from the bucket of `key`, which holds `burst` tokens and refills at `rate` per
+/// second. False, and nothing is taken, when the bucket has less than one token left.
+fn take<K: Ord + Copy>(buckets: &Mutex<BTreeMap<K, Bucket>>, key: K, rate: f64, burst: f64, cost: f64) -> bool {
+    let now = Instant::now();
+    let mut buckets = buckets.lock().unwrap();
+    if buckets.len() >= MAX_BUCKETS && !buckets.contains_key(&key) {
+        buckets.retain(|_, bucket| {
+            bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate < burst
+        });
+    }
+    let bucket = buckets.entry(key).or_insert(Bucket { tokens: burst, updated: now });
+    let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+    bucket.tokens = (bucket.tokens + elapsed * rate).min(burst);
+    bucket.updated = now;
+    if bucket.tokens >= 1.0 {
+        bucket.tokens -= cost;
+        true
+    } else {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits() {
+        let config = Config {
+            max_connections: 3,
+            max_connections_per_ip: 2,
+            commands_per_second: 0.001,
+            command_burst: 2,
+            ..Config::default()
+        };
+        let a = Some("192.0.2.1".parse().unwrap());
+        let b = Some("192.0.2.2".parse().unwrap());
+        let first = connect(&config, a).unwrap();
+        let _second = connect(&config, a).unwrap();
+        assert!(connect(&config, a).is_none());
+        let _third = connect(&config, b).unwrap();
+        assert!(connect(&config, b).is_none());
+        drop(first);
+        assert!(connect(&config, a).is_some());
+
+        assert!(command(&config, Client::Address(a)));
+        assert!(command(&config, Client::Address(a)));
+        assert!(!command(&config, Client::Address(a)));
+        assert!(command(&config, Client::Address(b)));
+
+        // websockets from the same address are limited separately
+        let first = SocketBucket::new();
+        let second = SocketBucket::new();
+        assert!(command(&config, first.client()));
+        assert!(command(&config, first.client()));
+        assert!(!command(&config, first.client()));
+        assert!(command(&config, second.client()));
+        let client = first.client();
+        drop(first);
+        assert!(!BUCKETS.lock().unwrap().contains_key(&client));
+    }
+}
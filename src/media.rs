@@ -0,0 +1,497 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::path::{Component, Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde::Serialize;
+use tokio::fs;
+use anyhow::Result;
+use crate::config::{Config, MediaRoot};
+use crate::logger::debug;
+
+#[derive(Debug)]
+pub enum AccessError {
+    NotFound,
+    Forbidden,
+}
+
+impl AccessError {
+    pub fn status(&self) -> u16 {
+        match self {
+            Self::NotFound => 404,
+            Self::Forbidden => 403,
+        }
+    }
+}
+
+impl Display for AccessError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotFound => write!(f, "no such file or directory"),
+            Self::Forbidden => write!(f, "path is outside of the media roots"),
+        }
+    }
+}
+
+impl std::error::Error for AccessError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    Video,
+    Audio,
+    Subtitle,
+    Playlist,
+    Image,
+    Other,
+}
+
+impl Kind {
+    pub fn from_path(path: &Path) -> Self {
+        let ext = path.extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_ascii_lowercase());
+        match ext.as_deref() {
+            Some("mkv" | "mp4" | "m4v" | "webm" | "avi" | "mov" | "wmv" | "flv" | "mpg" | "mpeg" | "ts" | "m2ts" | "ogv" | "3gp") => Self::Video,
+            Some("mp3" | "flac" | "ogg" | "opus" | "m4a" | "aac" | "wav" | "wma" | "alac" | "ape" | "wv" | "mka") => Self::Audio,
+            Some("srt" | "ass" | "ssa" | "vtt" | "sub" | "idx" | "sup") => Self::Subtitle,
+            Some("m3u" | "m3u8" | "pls" | "xspf" | "cue") => Self::Playlist,
+            Some("jpg" | "jpeg" | "png" | "gif" | "webp" | "bmp" | "avif") => Self::Image,
+            _ => Self::Other,
+        }
+    }
+
+    /// content type browsers expect for a media file, a guess for the containers mpv plays
+    pub fn mime_type(path: &Path) -> &'static str {
+        let ext = path.extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_ascii_lowercase());
+        match ext.as_deref() {
+            Some("mp4" | "m4v") => "video/mp4",
+            Some("webm") => "video/webm",
+            Some("mkv") => "video/x-matroska",
+            Some("mov") => "video/quicktime",
+            Some("avi") => "video/x-msvideo",
+            Some("ogv") => "video/ogg",
+            Some("ts" | "m2ts") => "video/mp2t",
+            Some("mpg" | "mpeg") => "video/mpeg",
+            Some("mp3") => "audio/mpeg",
+            Some("flac") => "audio/flac",
+            Some("ogg" | "opus") => "audio/ogg",
+            Some("m4a") => "audio/mp4",
+            Some("aac") => "audio/aac",
+            Some("wav") => "audio/wav",
+            Some("mka") => "audio/x-matroska",
+            Some("vtt") => "text/vtt",
+            _ => crate::assets::mime_type(path),
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "video" => Some(Self::Video),
+            "audio" => Some(Self::Audio),
+            "subtitle" => Some(Self::Subtitle),
+            "playlist" => Some(Self::Playlist),
+            "image" => Some(Self::Image),
+            "other" => Some(Self::Other),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Entry {
+    pub name: String,
+    /// size in bytes, not set for directories
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    /// modification time in seconds since the unix epoch
+    pub mtime: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kind: Option<Kind>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Listing {
+    pub dirs: Vec<Entry>,
+    pub files: Vec<Entry>,
+    /// number of entries before paging
+    pub total: usize,
+    pub offset: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SortBy {
+    Name,
+    Size,
+    Mtime,
+}
+
+/// Query options of a file picker listing
+#[derive(Debug)]
+pub struct ListOptions {
+    pub sort: SortBy,
+    pub descending: bool,
+    /// only list files with these extensions, lowercase without the dot
+    pub extensions: Vec<String>,
+    pub kinds: Vec<Kind>,
+    pub hidden: bool,
+    pub offset: usize,
+    pub limit: Option<usize>,
+}
+
+impl Default for ListOptions {
+    fn default() -> Self {
+        ListOptions {
+            sort: SortBy::Name,
+            descending: false,
+            extensions: Vec::new(),
+            kinds: Vec::new(),
+            hidden: false,
+            offset: 0,
+            limit: None,
+        }
+    }
+}
+
+impl ListOptions {
+    /// Reads `sort=name|size|mtime`, `order=asc|desc`, `ext=mkv,mp4`, `kind=video,audio`,
+    /// `hidden=true`, `offset` and `limit`. Unknown values fall back to the defaults.
+    pub fn from_query(query: &HashMap<String, String>) -> Self {
+        let list = |key: &str| -> Vec<String> {
+            query.get(key).map_or(Vec::new(), |v| {
+                v.split(',')
+                    .map(|item| item.trim().trim_start_matches('.').to_ascii_lowercase())
+                    .filter(|item| !item.is_empty())
+                    .collect()
+            })
+        };
+        ListOptions {
+            sort: match query.get("sort").map(|s| s.as_str()) {
+                Some("size") => SortBy::Size,
+                Some("mtime") => SortBy::Mtime,
+                _ => SortBy::Name,
+            },
+            descending: query.get("order").is_some_and(|o| o == "desc"),
+            extensions: list("ext"),
+            kinds: list("kind").iter().filter_map(|k| Kind::from_name(k)).collect(),
+            hidden: query.get("hidden").is_some_and(|h| h == "true" || h == "1"),
+            offset: query.get("offset").and_then(|o| o.parse().ok()).unwrap_or(0),
+            limit: query.get("limit").and_then(|l| l.parse().ok()),
+        }
+    }
+
+    fn matches(&self, name: &str, kind: Kind) -> bool {
+        let ext = Path::new(name).extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_ascii_lowercase());
+        let ext_ok = self.extensions.is_empty() || ext.is_some_and(|ext| self.extensions.contains(&ext));
+        let kind_ok = self.kinds.is_empty() || self.kinds.contains(&kind);
+        ext_ok && kind_ok
+    }
+
+    fn compare(&self, a: &Entry, b: &Entry) -> Ordering {
+        let ord = match self.sort {
+            SortBy::Name => Ordering::Equal,
+            SortBy::Size => a.size.cmp(&b.size),
+            SortBy::Mtime => a.mtime.cmp(&b.mtime),
+        }.then_with(|| natural_cmp(&a.name, &b.name));
+        if self.descending { ord.reverse() } else { ord }
+    }
+}
+
+/// Case insensitive comparison treating runs of digits as numbers, so "ep2" sorts before "ep10".
+pub fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let mut a = a.chars().peekable();
+    let mut b = b.chars().peekable();
+    loop {
+        match (a.peek().copied(), b.peek().copied()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let take_number = |chars: &mut std::iter::Peekable<std::str::Chars>| {
+                    let mut digits = String::new();
+                    while let Some(c) = chars.next_if(|c| c.is_ascii_digit()) {
+                        digits.push(c);
+                    }
+                    digits
+                };
+                let (x, y) = (take_number(&mut a), take_number(&mut b));
+                let (x_trim, y_trim) = (x.trim_start_matches('0'), y.trim_start_matches('0'));
+                let ord = x_trim.len().cmp(&y_trim.len())
+                    .then_with(|| x_trim.cmp(y_trim))
+                    .then_with(|| x.len().cmp(&y.len()));
+                if ord != Ordering::Equal {
+                    return ord;
+                }
+            },
+            (Some(x), Some(y)) => {
+                let ord = x.to_lowercase().cmp(y.to_lowercase());
+                if ord != Ordering::Equal {
+                    return ord;
+                }
+                a.next();
+                b.next();
+            },
+        }
+    }
+}
+
+/// The configured media roots, or the working directory of mpv when none are configured.
+pub fn roots(config: &Config) -> Vec<MediaRoot> {
+    if !config.media_roots.is_empty() {
+        return config.media_roots.clone();
+    }
+    match std::env::current_dir() {
+        Ok(path) => vec![MediaRoot { name: "files".into(), path }],
+        Err(_) => Vec::new(),
+    }
+}
+
+/// whether `path` may be handed out, given that it is somewhere below the root at `root`
+fn allowed(config: &Config, root: &Path, path: &Path) -> bool {
+    if config.follow_symlinks {
+        return true;
+    }
+    match path.canonicalize() {
+        Ok(canonical) => canonical.starts_with(root),
+        Err(_) => false,
+    }
+}
+
+/// canonical path of the media root called `name`
+fn root_dir(config: &Config, name: &str) -> Result<PathBuf, AccessError> {
+    let root = roots(config).into_iter()
+        .find(|root| root.name == name)
+        .ok_or(AccessError::NotFound)?;
+    root.path.canonicalize().map_err(|_| AccessError::NotFound)
+}
+
+/// Resolves a file picker path of the form `<root name>/<path below the root>` to a path on disk.
+/// `..` components are refused, as are symlinks leading out of the root unless `follow_symlinks`
+/// is set.
+pub fn resolve(config: &Config, path: &str) -> Result<PathBuf, AccessError> {
+    let mut parts = path.split('/').filter(|p| !p.is_empty());
+    let root = root_dir(config, parts.next().ok_or(AccessError::NotFound)?)?;
+    let mut resolved = root.clone();
+    for part in parts {
+        let mut components = Path::new(part).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(c)), None) => resolved.push(c),
+            _ => return Err(AccessError::Forbidden),
+        }
+    }
+    if !resolved.exists() {
+        return Err(AccessError::NotFound);
+    }
+    if !allowed(config, &root, &resolved) {
+        return Err(AccessError::Forbidden);
+    }
+    Ok(resolved)
+}
+
+fn unix_time(time: std::io::Result<SystemTime>) -> Option<u64> {
+    time.ok()?.duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs())
+}
+
+/// Checks that a path from outside the file picker, such as mpv's `path`, is a file inside one of the
+/// media roots, and returns its canonical path. Symlinks leading out of a root only count as inside it
+/// when `follow_symlinks` is set.
+pub fn within_roots(config: &Config, path: &Path) -> Result<PathBuf, AccessError> {
+    let canonical = path.canonicalize().map_err(|_| AccessError::NotFound)?;
+    let inside = roots(config).iter().any(|root| {
+        let Ok(root) = root.path.canonicalize() else { return false };
+        canonical.starts_with(&root)
+            || (config.follow_symlinks && path.is_absolute() && path.components().all(|c| c != Component::ParentDir) && path.starts_with(&root))
+    });
+    if !inside {
+        return Err(AccessError::Forbidden);
+    }
+    Ok(canonical)
+}
+
+/// whether `location` is a URL rather than a path, going by its scheme
+pub fn is_url(location: &str) -> bool {
+    location.split_once("://").is_some_and(|(scheme, _)| {
+        !scheme.is_empty() && scheme.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'))
+    })
+}
+
+/// Checks a location a client wants mpv to load: URLs must use one of the configured `url_schemes`, as
+/// others could make mpv read local devices or run filters, and local files must be inside the media
+/// roots. Relative paths are looked up in `base`, such as the directory of a playlist from the file
+/// picker, or else in each media root. Returns the location to load.
+pub fn check_location(config: &Config, location: &str, base: Option<&Path>) -> Result<String, String> {
+    let path = match location.split_once("://") {
+        Some(("file", path)) => PathBuf::from(crate::server::percent_decode(path.strip_prefix("localhost").unwrap_or(path))),
+        Some((scheme, _)) if is_url(location) => {
+            if config.url_schemes.iter().any(|allowed| allowed.eq_ignore_ascii_case(scheme)) {
+                return Ok(location.to_string());
+            }
+            return Err(format!("\"{scheme}\" urls are not allowed"));
+        },
+        _ => PathBuf::from(location),
+    };
+    let path = if path.is_absolute() {
+        path
+    } else if let Some(base) = base {
+        base.join(path)
+    } else {
+        roots(config).into_iter()
+            .map(|root| root.path.join(&path))
+            .find(|candidate| candidate.exists())
+            .ok_or("not found in the media roots".to_string())?
+    };
+    let path = within_roots(config, &path).map_err(|e| e.to_string())?;
+    if !path.is_file() {
+        return Err("not a file".into());
+    }
+    path.into_os_string().into_string().map_err(|_| "path is not utf-8".to_string())
+}
+
+/// Lists a file picker directory. The top level lists the media roots themselves. Anything that is
+/// not a regular file or directory, such as sockets, devices and broken symlinks, is left out.
+/// Directories are listed before files, paging applies to both together.
+pub async fn list(config: &Config, path: &str, options: &ListOptions) -> Result<Listing> {
+    let (mut dirs, mut files) = (Vec::new(), Vec::new());
+    if path.split('/').all(|p| p.is_empty()) {
+        for root in roots(config) {
+            let mtime = fs::metadata(&root.path).await.ok().and_then(|m| unix_time(m.modified()));
+            dirs.push(Entry { name: root.name, size: None, mtime, kind: None });
+        }
+    } else {
+        let dir = resolve(config, path)?;
+        let root = root_dir(config, path.split('/').find(|p| !p.is_empty()).unwrap_or(""))?;
+        let mut entries = fs::read_dir(&dir).await.map_err(|_| AccessError::NotFound)?;
+        while let Some(entry) = entries.next_entry().await? {
+            let Ok(name) = entry.file_name().into_string() else {
+                debug!("skipping non utf-8 file name {:?}", entry.file_name());
+                continue;
+            };
+            if name.starts_with('.') && !options.hidden {
+                continue;
+            }
+            let entry_path = entry.path();
+            if entry.file_type().await?.is_symlink() && !allowed(config, &root, &entry_path) {
+                continue;
+            }
+            // follows symlinks, so broken links fail here and are skipped
+            let Ok(metadata) = fs::metadata(&entry_path).await else { continue };
+            let mtime = unix_time(metadata.modified());
+            if metadata.is_dir() {
+                dirs.push(Entry { name, size: None, mtime, kind: None });
+            } else if metadata.is_file() {
+                let kind = Kind::from_path(&entry_path);
+                if options.matches(&name, kind) {
+                    files.push(Entry { name, size: Some(metadata.len()), mtime, kind: Some(kind) });
+                }
+            } else {
+                debug!("skipping special file {entry_path:?}");
+            }
+        }
+    }
+    dirs.sort_by(|a, b| options.compare(a, b));
+    files.sort_by(|a, b| options.compare(a, b));
+
+    let total = dirs.len() + files.len();
+    let limit = options.limit.unwrap_or(usize::MAX);
+    let dirs_shown: Vec<Entry> = dirs.into_iter().skip(options.offset).take(limit).collect();
+    let files_offset = options.offset.saturating_sub(total - files.len());
+    let files_limit = limit - dirs_shown.len();
+    let files = files.into_iter().skip(files_offset).take(files_limit).collect();
+    Ok(Listing { dirs: dirs_shown, files, total, offset: options.offset })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn natural_order() {
+        let mut names = vec!["ep10.mkv", "Ep2.mkv", "ep1.mkv", "ep02.mkv", "extras"];
+        names.sort_by(|a, b| natural_cmp(a, b));
+        assert_eq!(names, vec!["ep1.mkv", "Ep2.mkv", "ep02.mkv", "ep10.mkv", "extras"]);
+    }
+
+    #[tokio::test]
+    async fn listing() {
+        let root = TempDir::new("listing");
+        for dir in ["a", "b"] {
+            std::fs::create_dir(root.join(dir)).unwrap();
+        }
+        for file in ["c.mkv", "d.mp3", "e.srt", ".f.mkv"] {
+            std::fs::write(root.join(file), b"").unwrap();
+        }
+        let config = Config {
+            media_roots: vec![MediaRoot { name: "Movies".into(), path: root.to_path_buf() }],
+            ..Config::default()
+        };
+        let names = |entries: &[Entry]| entries.iter().map(|e| e.name.clone()).collect::<Vec<_>>();
+        let fetch = |options: ListOptions| {
+            let config = &config;
+            async move { list(config, "Movies", &options).await.unwrap() }
+        };
+
+        // a page that starts among the directories and ends among the files
+        let page = fetch(ListOptions { offset: 1, limit: Some(2), ..ListOptions::default() }).await;
+        assert_eq!(names(&page.dirs), ["b"]);
+        assert_eq!(names(&page.files), ["c.mkv"]);
+        assert_eq!((page.total, page.offset), (5, 1));
+        let page = fetch(ListOptions { offset: 3, limit: Some(10), ..ListOptions::default() }).await;
+        assert!(page.dirs.is_empty());
+        assert_eq!(names(&page.files), ["d.mp3", "e.srt"]);
+
+        let mkv = fetch(ListOptions { extensions: vec!["mkv".into()], ..ListOptions::default() }).await;
+        // the filters only apply to files
+        assert_eq!(names(&mkv.dirs), ["a", "b"]);
+        assert_eq!(names(&mkv.files), ["c.mkv"]);
+        let audio = fetch(ListOptions { kinds: vec![Kind::Audio], ..ListOptions::default() }).await;
+        assert_eq!(names(&audio.files), ["d.mp3"]);
+        let hidden = fetch(ListOptions { hidden: true, ..ListOptions::default() }).await;
+        assert_eq!(names(&hidden.files), [".f.mkv", "c.mkv", "d.mp3", "e.srt"]);
+    }
+
+    #[test]
+    fn resolve_stays_in_roots() {
+        let base = TempDir::new("media");
+        let root = base.join("root");
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        std::fs::write(root.join("sub/a.mkv"), b"").unwrap();
+        std::fs::write(base.join("secret"), b"").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(base.join("secret"), root.join("link")).unwrap();
+
+        let mut config = Config::default();
+        config.media_roots = vec![MediaRoot { name: "Movies".into(), path: root.clone() }];
+        assert!(resolve(&config, "Movies/sub/a.mkv").is_ok());
+        assert!(matches!(resolve(&config, "Movies/../secret"), Err(AccessError::Forbidden)));
+        assert!(matches!(resolve(&config, "Movies/missing"), Err(AccessError::NotFound)));
+        assert!(matches!(resolve(&config, "Other/sub"), Err(AccessError::NotFound)));
+        #[cfg(unix)]
+        {
+            assert!(matches!(resolve(&config, "Movies/link"), Err(AccessError::Forbidden)));
+            config.follow_symlinks = true;
+            assert!(resolve(&config, "Movies/link").is_ok());
+        }
+    }
+
+    #[test]
+    fn locations() {
+        let base = TempDir::new("locations");
+        std::fs::write(base.join("a.mkv"), b"").unwrap();
+        let config = Config {
+            media_roots: vec![MediaRoot { name: "Movies".into(), path: base.to_path_buf() }],
+            ..Config::default()
+        };
+        assert_eq!(check_location(&config, "https://example.com/a.mkv", None), Ok("https://example.com/a.mkv".into()));
+        assert!(check_location(&config, "a.mkv", None).is_ok());
+        assert!(check_location(&config, "/etc/passwd", None).is_err());
+        assert!(check_location(&config, "av://v4l2:/dev/video0", None).is_err());
+        assert!(check_location(&config, "lavfi://sine", None).is_err());
+        assert!(check_location(&config, "ftp://nas/a.mkv", None).is_ok());
+        assert!(check_location(&config, "SMB://nas/a.mkv", None).is_ok());
+    }
+}
@@ -0,0 +1,154 @@
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::future::Future;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use crate::mpv::CmdHandle;
+
+/// first path components that get their own `route` label, anything else is counted as "other"
+const ROUTES: &[&str] = &["", "login", "static", "api", "socket", "events", "file-picker", "media", "metrics", "manifest.webmanifest", "icons", "sw.js"];
+/// endpoints under `/api` that get a label of their own
+const API_ENDPOINTS: &[&str] = &[
+    "status", "toggle-play", "toggle-fullscreen", "stop", "shutdown", "seek", "skip", "volume", "play-now",
+    "playlist", "sub-add", "upload", "subtitles", "artwork", "screenshot", "playlist.m3u8", "playlist.xspf",
+    "playlist.json",
+];
+
+static WS_CLIENTS: AtomicI64 = AtomicI64::new(0);
+static LAGGED_EVENTS: AtomicU64 = AtomicU64::new(0);
+static COMMAND_ERRORS: AtomicU64 = AtomicU64::new(0);
+static REQUESTS: Mutex<BTreeMap<(String, u16), u64>> = Mutex::new(BTreeMap::new());
+static COMMANDS: Mutex<BTreeMap<String, u64>> = Mutex::new(BTreeMap::new());
+
+tokio::task_local! {
+    /// status of the last response written on the current connection
+    static STATUS: Cell<Option<u16>>;
+}
+
+/// Runs a connection with its own response status slot, read back with `take_status`.
+pub async fn scope<F: Future>(connection: F) -> F::Output {
+    STATUS.scope(Cell::new(None), connection).await
+}
+
+/// called by `Response::head`, as `Response::write_to` writes it, with the response status
+pub fn response_status(status: u16) {
+    let _ = STATUS.try_with(|s| s.set(Some(status)));
+}
+
+pub fn take_status() -> Option<u16> {
+    STATUS.try_with(|s| s.take()).ok().flatten()
+}
+
+/// The `route` label for a request path: its first component, plus the endpoint name for known `/api` endpoints.
+/// Unknown paths share one label so clients cannot create new series at will.
+pub fn route_label(path: &str, status: u16) -> String {
+    let mut parts = path.trim_start_matches('/').split('/');
+    let first = parts.next().unwrap_or("");
+    if !ROUTES.contains(&first) {
+        return "other".into();
+    }
+    match (first, parts.next()) {
+        ("api", Some(endpoint)) if status != 401 && API_ENDPOINTS.contains(&endpoint) => format!("/api/{endpoint}"),
+        _ => format!("/{first}"),
+    }
+}
+
+pub fn request(route: String, status: u16) {
+    *REQUESTS.lock().unwrap().entry((route, status)).or_default() += 1;
+}
+
+pub fn command(event: &str) {
+    *COMMANDS.lock().unwrap().entry(event.into()).or_default() += 1;
+}
+
+pub fn command_error() {
+    COMMAND_ERRORS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// events a client's receiver dropped because it fell behind the broadcaster
+pub fn lagged(count: u64) {
+    LAGGED_EVENTS.fetch_add(count, Ordering::Relaxed);
+}
+
+/// Counts a connected websocket client for as long as it is alive.
+pub struct WsClient;
+
+impl WsClient {
+    pub fn connect() -> Self {
+        WS_CLIENTS.fetch_add(1, Ordering::Relaxed);
+        WsClient
+    }
+}
+
+impl Drop for WsClient {
+    fn drop(&mut self) {
+        WS_CLIENTS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+fn escape(label: &str) -> String {
+    label.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+/// Renders every metric in the Prometheus text exposition format. Player gauges are left out while
+/// mpv has no value for them, e.g. `duration` when nothing is loaded.
+pub fn render(cmd_handle: &mut CmdHandle<'_>) -> String {
+    let mut out = String::new();
+    header(&mut out, "mpv_remote_websocket_clients", "gauge", "Connected websocket clients.");
+    let _ = writeln!(out, "mpv_remote_websocket_clients {}", WS_CLIENTS.load(Ordering::Relaxed));
+
+    header(&mut out, "mpv_remote_http_requests_total", "counter", "HTTP requests by route and response status.");
+    for ((route, status), count) in REQUESTS.lock().unwrap().iter() {
+        let _ = writeln!(out, "mpv_remote_http_requests_total{{route=\"{}\",status=\"{status}\"}} {count}", escape(route));
+    }
+
+    header(&mut out, "mpv_remote_commands_total", "counter", "Client commands handled by event name.");
+    for (event, count) in COMMANDS.lock().unwrap().iter() {
+        let _ = writeln!(out, "mpv_remote_commands_total{{event=\"{}\"}} {count}", escape(event));
+    }
+    header(&mut out, "mpv_remote_command_errors_total", "counter", "Client commands that failed.");
+    let _ = writeln!(out, "mpv_remote_command_errors_total {}", COMMAND_ERRORS.load(Ordering::Relaxed));
+
+    header(&mut out, "mpv_remote_lagged_events_total", "counter", "Player events dropped by clients that fell behind.");
+    let _ = writeln!(out, "mpv_remote_lagged_events_total {}", LAGGED_EVENTS.load(Ordering::Relaxed));
+
+    let gauges = [
+        ("mpv_time_pos_seconds", "Playback position.", cmd_handle.get_property::<f64>("time-pos").ok()),
+        ("mpv_duration_seconds", "Duration of the current file.", cmd_handle.get_property::<f64>("duration").ok()),
+        ("mpv_volume", "Audio output volume.", cmd_handle.get_property::<i64>("ao-volume").ok().map(|v| v as f64)),
+        ("mpv_pause", "1 when playback is paused.", cmd_handle.get_property::<bool>("pause").ok().map(|p| p as u8 as f64)),
+        ("mpv_cache_duration_seconds", "Seconds of media buffered ahead by the demuxer cache.",
+            cmd_handle.get_property::<f64>("demuxer-cache-duration").ok()),
+        ("mpv_cache_buffering_percent", "Cache fill level while buffering.",
+            cmd_handle.get_property::<i64>("cache-buffering-state").ok().map(|v| v as f64)),
+    ];
+    for (name, help, value) in gauges {
+        let Some(value) = value else { continue };
+        header(&mut out, name, "gauge", help);
+        let _ = writeln!(out, "{name} {value}");
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn route_labels() {
+        assert_eq!(route_label("/", 200), "/");
+        assert_eq!(route_label("/static/main.js", 200), "/static");
+        assert_eq!(route_label("/api/playlist/3", 200), "/api/playlist");
+        assert_eq!(route_label("/api/made-up", 404), "/api");
+        // unknown endpoints share a label whatever the status
+        assert_eq!(route_label("/api/made-up", 405), "/api");
+        assert_eq!(route_label("/api/playlist.m3u8", 200), "/api/playlist.m3u8");
+        assert_eq!(route_label("/wp-admin/x", 404), "other");
+    }
+}
@@ -1,6 +1,7 @@
 use mpv_client;
 use serde_json::json;
 use anyhow::{Result, anyhow};
+use std::ffi::{c_char, CString};
 use std::ops::{Deref, DerefMut};
 use std::sync::Arc;
 use tokio::sync::broadcast;
@@ -42,6 +43,16 @@ impl Event {
     }
 }
 
+/// Resolves once mpv is shutting down, or the broadcaster is gone.
+pub async fn shutdown(mut event_chan: broadcast::Receiver<Event>) {
+    loop {
+        match event_chan.recv().await {
+            Ok(Event::Shutdown) | Err(broadcast::error::RecvError::Closed) => return,
+            _ => continue,
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct EventBroadcaster(Arc<broadcast::Sender<Event>>);
 pub type EventSubscriber = Arc<dyn Fn() -> broadcast::Receiver<Event> + Send + Sync>;
@@ -80,6 +91,39 @@ impl<'a> CmdHandle<'a> {
         panic!()
     }
 
+    /// Runs a command that returns a value, such as `screenshot-raw`. mpv_client has no wrapper for
+    /// mpv_command_ret so it is called directly.
+    pub fn command_ret<I, S>(&mut self, args: I) -> Result<mpv_client::Node>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let args = args.into_iter()
+            .map(|s| CString::new(s.as_ref()))
+            .collect::<Result<Vec<CString>, _>>()?;
+        let mut raw_args: Vec<*const c_char> = args.iter().map(|s| s.as_ptr()).collect();
+        raw_args.push(std::ptr::null());
+        let mut node = mpv_client_sys::mpv_node {
+            format: 0,
+            u: mpv_client_sys::mpv_node__bindgen_ty_1 { int64: 0 },
+        };
+        unsafe {
+            let err = mpv_client_sys::mpv_command_ret(self.as_mut_ptr(), raw_args.as_mut_ptr(), &mut node);
+            if err < 0 {
+                return Err(anyhow!(mpv_client::Error::new(err)));
+            }
+            let result = mpv_client::node::from_mpv_node(&mut node);
+            mpv_client_sys::mpv_free_node_contents(&mut node);
+            Ok(result)
+        }
+    }
+
+    /// mpv's `playlist` property as JSON
+    pub fn playlist(&mut self) -> Option<serde_json::Value> {
+        let playlist = self.get_property::<String>("playlist").ok()?;
+        serde_json::from_str(playlist.as_str()).ok()
+    }
+
     pub fn status(&mut self) -> serde_json::Value {
         let duration = self.get_property::<f64>("duration").ok();
         let title = self.get_property::<String>("media-title").ok();
@@ -88,10 +132,7 @@ impl<'a> CmdHandle<'a> {
         let time_pos = self.get_property::<f64>("time-pos").ok();
         let volume = self.get_property::<i64>("ao-volume").ok();
         let core_idle = self.get_property::<bool>("core-idle").ok();
-        let playlist: Option<serde_json::Value> = match self.get_property::<String>("playlist").ok() {
-            Some(s) => serde_json::from_str(s.as_str()).ok(),
-            None => None,
-        };
+        let playlist = self.playlist();
         json!({
             "duration": duration,
             "media-title": title,
@@ -101,6 +142,7 @@ impl<'a> CmdHandle<'a> {
             "time-pos": time_pos,
             "volume": volume,
             "core-idle": core_idle,
+            "artwork": crate::artwork::available(),
         })
     }
 }
@@ -0,0 +1,413 @@
+use std::path::{Path, PathBuf};
+use serde::Serialize;
+use serde_json::{json, Value};
+use tokio::io::{AsyncReadExt, AsyncWrite};
+use anyhow::{Result, anyhow, bail};
+use crate::config::Config;
+use crate::logger::warning;
+use crate::media;
+use crate::mpv::CmdHandle;
+use crate::server::{Method, Request, Response, json_error};
+
+/// A playlist entry as it is exported.
+#[derive(Debug, Serialize, PartialEq)]
+pub struct Entry {
+    /// absolute path of a local file, or a URL
+    pub location: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    pub current: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    M3u8,
+    Xspf,
+    Json,
+}
+
+impl Format {
+    pub fn from_extension(ext: &str) -> Option<Self> {
+        match ext {
+            "m3u8" => Some(Self::M3u8),
+            "xspf" => Some(Self::Xspf),
+            "json" => Some(Self::Json),
+            _ => None,
+        }
+    }
+
+    fn mime(&self) -> &'static str {
+        match self {
+            Self::M3u8 => "audio/x-mpegurl; charset=utf-8",
+            Self::Xspf => "application/xspf+xml",
+            Self::Json => "application/json",
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            Self::M3u8 => "m3u8",
+            Self::Xspf => "xspf",
+            Self::Json => "json",
+        }
+    }
+}
+
+/// The entries of mpv's playlist, the same data `status` sends. Relative paths are resolved against
+/// mpv's working directory, URLs are kept as they are.
+pub fn entries(playlist: &Value, working_dir: &Path) -> Vec<Entry> {
+    let Some(items) = playlist.as_array() else { return Vec::new() };
+    items.iter().filter_map(|item| {
+        let filename = item.get("filename")?.as_str()?;
+        let location = if media::is_url(filename) {
+            filename.to_string()
+        } else {
+            working_dir.join(filename).to_string_lossy().into_owned()
+        };
+        Some(Entry {
+            location,
+            title: item.get("title").and_then(Value::as_str).map(String::from),
+            current: item.get("current").and_then(Value::as_bool).unwrap_or(false),
+        })
+    }).collect()
+}
+
+/// percent-encodes everything in `s` except unreserved characters and `/`
+fn percent_encode(s: &str) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for byte in s.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => encoded.push(byte as char),
+            byte => encoded += &format!("%{byte:02X}"),
+        }
+    }
+    encoded
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;").replace('\'', "&apos;")
+}
+
+/// the entry's location as a URI, XSPF has no plain paths
+fn uri(location: &str) -> String {
+    if media::is_url(location) {
+        location.to_string()
+    } else {
+        format!("file://{}", percent_encode(location))
+    }
+}
+
+pub fn m3u8(entries: &[Entry]) -> String {
+    let mut out = String::from("#EXTM3U\n");
+    for entry in entries {
+        if let Some(title) = &entry.title {
+            // the duration of entries that are not playing is unknown
+            out += &format!("#EXTINF:-1,{}\n", title.replace(['\r', '\n'], " "));
+        }
+        out += &entry.location;
+        out.push('\n');
+    }
+    out
+}
+
+pub fn xspf(entries: &[Entry]) -> String {
+    let mut out = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<playlist version=\"1\" xmlns=\"http://xspf.org/ns/0/\">\n  <trackList>\n");
+    for entry in entries {
+        out += "    <track>\n";
+        out += &format!("      <location>{}</location>\n", xml_escape(&uri(&entry.location)));
+        if let Some(title) = &entry.title {
+            out += &format!("      <title>{}</title>\n", xml_escape(title));
+        }
+        out += "    </track>\n";
+    }
+    out += "  </trackList>\n</playlist>\n";
+    out
+}
+
+/// An entry read from an imported playlist, before it is checked.
+#[derive(Debug, PartialEq)]
+pub struct Item {
+    pub location: String,
+    pub title: Option<String>,
+}
+
+/// An imported entry that was not loaded, and why.
+#[derive(Debug, Serialize)]
+pub struct Skipped {
+    pub entry: String,
+    pub reason: String,
+}
+
+/// Reads an M3U/M3U8, PLS or XSPF document, telling them apart by their content.
+pub fn parse(document: &str) -> Vec<Item> {
+    let document = document.trim_start_matches('\u{feff}').trim_start();
+    if document.starts_with('<') {
+        parse_xspf(document)
+    } else if document.lines().next().is_some_and(|line| line.trim().eq_ignore_ascii_case("[playlist]")) {
+        parse_pls(document)
+    } else {
+        parse_m3u(document)
+    }
+}
+
+fn parse_m3u(document: &str) -> Vec<Item> {
+    let mut items = Vec::new();
+    let mut title = None;
+    for line in document.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        if let Some(info) = line.strip_prefix("#EXTINF:") {
+            title = info.split_once(',').map(|(_, t)| t.trim().to_string()).filter(|t| !t.is_empty());
+        } else if !line.starts_with('#') {
+            items.push(Item { location: line.to_string(), title: title.take() });
+        }
+    }
+    items
+}
+
+fn parse_pls(document: &str) -> Vec<Item> {
+    let mut entries: std::collections::BTreeMap<u32, Item> = std::collections::BTreeMap::new();
+    let mut titles = Vec::new();
+    for line in document.lines() {
+        let Some((key, value)) = line.trim().split_once('=') else { continue };
+        let key = key.trim().to_ascii_lowercase();
+        if let Some(n) = key.strip_prefix("file").and_then(|n| n.parse().ok()) {
+            entries.insert(n, Item { location: value.trim().to_string(), title: None });
+        } else if let Some(n) = key.strip_prefix("title").and_then(|n| n.parse::<u32>().ok()) {
+            titles.push((n, value.trim().to_string()));
+        }
+    }
+    for (n, title) in titles {
+        if let Some(item) = entries.get_mut(&n) {
+            item.title = Some(title);
+        }
+    }
+    entries.into_values().collect()
+}
+
+fn xml_unescape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(i) = rest.find('&') {
+        out += &rest[.. i];
+        rest = &rest[i ..];
+        let Some(end) = rest.find(';') else { break };
+        let decoded = match &rest[1 .. end] {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            entity => entity.strip_prefix("#x").or(entity.strip_prefix("#X"))
+                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                .or_else(|| entity.strip_prefix('#').and_then(|dec| dec.parse().ok()))
+                .and_then(char::from_u32),
+        };
+        match decoded {
+            Some(c) => {
+                out.push(c);
+                rest = &rest[end + 1 ..];
+            },
+            None => {
+                out.push('&');
+                rest = &rest[1 ..];
+            },
+        }
+    }
+    out + rest
+}
+
+/// text of the first `<tag>` element in `xml`
+fn element<'a>(xml: &'a str, tag: &str) -> Option<&'a str> {
+    let start = xml.find(&format!("<{tag}>"))? + tag.len() + 2;
+    let end = xml[start ..].find(&format!("</{tag}>"))?;
+    Some(xml[start .. start + end].trim())
+}
+
+fn parse_xspf(document: &str) -> Vec<Item> {
+    let mut items = Vec::new();
+    let mut rest = document;
+    while let Some(start) = rest.find("<track>") {
+        let Some(end) = rest[start ..].find("</track>") else { break };
+        let track = &rest[start .. start + end];
+        if let Some(location) = element(track, "location") {
+            items.push(Item {
+                location: xml_unescape(location),
+                title: element(track, "title").map(xml_unescape),
+            });
+        }
+        rest = &rest[start + end ..];
+    }
+    items
+}
+
+/// Checks imported entries with `media::check_location` before they are loaded.
+pub fn validate(config: &Config, items: Vec<Item>, base: Option<&Path>) -> (Vec<String>, Vec<Skipped>) {
+    let mut accepted = Vec::new();
+    let mut skipped = Vec::new();
+    for item in items {
+        match media::check_location(config, &item.location, base) {
+            Ok(location) => accepted.push(location),
+            Err(reason) => skipped.push(Skipped { entry: item.location, reason }),
+        }
+    }
+    (accepted, skipped)
+}
+
+/// How imported entries are loaded, the modes of mpv's `loadfile`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    /// replace the playlist and play the first entry, like play-now
+    Replace,
+    /// append to the playlist and start playing if nothing is, like playlist-add
+    AppendPlay,
+}
+
+impl Mode {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "replace" => Some(Self::Replace),
+            "append-play" => Some(Self::AppendPlay),
+            _ => None,
+        }
+    }
+}
+
+/// Reads a playlist file picked in the file picker. Other kinds of files are refused, and so are
+/// playlists over `limit` bytes, the same limit as for one sent in a request body.
+pub async fn read_file(path: &Path, limit: u64) -> Result<String> {
+    if media::Kind::from_path(path) != media::Kind::Playlist {
+        bail!("{:?} is not a playlist", path.file_name().unwrap_or_default());
+    }
+    let file = tokio::fs::File::open(path).await?;
+    if file.metadata().await?.len() > limit {
+        bail!("playlist is larger than {limit} bytes");
+    }
+    let mut document = String::new();
+    // the file could grow after the check
+    file.take(limit).read_to_string(&mut document).await?;
+    Ok(document)
+}
+
+/// Parses, checks and loads a playlist document. Returns what was loaded and what was skipped.
+pub fn import(config: &Config, cmd_handle: &mut CmdHandle<'_>, document: &str, base: Option<&Path>, mode: Mode) -> Result<Value> {
+    let items = parse(document);
+    if items.is_empty() {
+        bail!("no entries found in the playlist");
+    }
+    let (accepted, skipped) = validate(config, items, base);
+    for skip in &skipped {
+        warning!("playlist import skipped \"{}\": {}", skip.entry, skip.reason);
+    }
+    for (i, location) in accepted.iter().enumerate() {
+        let flag = match mode {
+            Mode::Replace if i == 0 => "replace",
+            Mode::Replace => "append",
+            Mode::AppendPlay => "append-play",
+        };
+        cmd_handle.command(["loadfile", location.as_str(), flag]).map_err(|e| anyhow!(e))?;
+    }
+    Ok(json!({
+        "loaded": accepted.len(),
+        "skipped": skipped,
+    }))
+}
+
+/// Serves `GET /api/playlist.<m3u8|xspf|json>`, the current playlist as a download.
+pub async fn export<T>(request: &Request, name: &str, stream: &mut T, cmd_handle: &mut CmdHandle<'_>) -> Result<()>
+where
+    T: AsyncWrite + Unpin,
+{
+    let Some(format) = name.strip_prefix("playlist.").and_then(Format::from_extension) else {
+        return json_error(request, stream, 404, &format!("unknown endpoint \"/api/{name}\"")).await;
+    };
+    if request.method != Method::GET {
+        return json_error(request, stream, 405, &format!("expected GET got {:?}", request.method)).await;
+    }
+    let working_dir = cmd_handle.get_property::<String>("working-directory")
+        .map(PathBuf::from)
+        .or_else(|_| std::env::current_dir())
+        .unwrap_or_default();
+    let entries = entries(&cmd_handle.playlist().unwrap_or(Value::Null), &working_dir);
+    let body = match format {
+        Format::M3u8 => m3u8(&entries),
+        Format::Xspf => xspf(&entries),
+        Format::Json => serde_json::to_string(&entries)?,
+    };
+    Response::new("HTTP/1.1", 200)
+        .headers(&request.response_headers)
+        .header("Content-Type", format.mime())
+        .header("Content-Disposition", &format!("attachment; filename=\"playlist.{}\"", format.extension()))
+        .header("Cache-Control", "no-store")
+        .body(body.as_bytes())
+        .write_to(stream).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn export_formats() {
+        let playlist = json!([
+            { "filename": "ep 1.mkv", "title": "Pilot & more", "current": true, "playing": true, "id": 1 },
+            { "filename": "https://example.com/stream.m3u8", "id": 2 },
+        ]);
+        let entries = entries(&playlist, Path::new("/media/show"));
+        assert_eq!(entries[0].location, "/media/show/ep 1.mkv");
+        assert!(entries[0].current);
+        assert_eq!(entries[1].location, "https://example.com/stream.m3u8");
+        assert_eq!(m3u8(&entries), "#EXTM3U\n#EXTINF:-1,Pilot & more\n/media/show/ep 1.mkv\nhttps://example.com/stream.m3u8\n");
+        let xspf = xspf(&entries);
+        assert!(xspf.contains("<location>file:///media/show/ep%201.mkv</location>"));
+        assert!(xspf.contains("<title>Pilot &amp; more</title>"));
+    }
+
+    #[test]
+    fn import_formats() {
+        let m3u = "#EXTM3U\n#EXTINF:120,First\n/a.mkv\n\n# comment\nhttp://example.com/b\n";
+        assert_eq!(parse(m3u), vec![
+            Item { location: "/a.mkv".into(), title: Some("First".into()) },
+            Item { location: "http://example.com/b".into(), title: None },
+        ]);
+        let pls = "[playlist]\nFile2=b.mp3\nFile1=a.mp3\nTitle1=A\nNumberOfEntries=2\nVersion=2\n";
+        assert_eq!(parse(pls), vec![
+            Item { location: "a.mp3".into(), title: Some("A".into()) },
+            Item { location: "b.mp3".into(), title: None },
+        ]);
+        let entries = vec![Entry { location: "/m/a & b.mkv".into(), title: Some("<A>".into()), current: false }];
+        assert_eq!(parse(&xspf(&entries)), vec![
+            Item { location: "file:///m/a%20%26%20b.mkv".into(), title: Some("<A>".into()) },
+        ]);
+    }
+
+    #[test]
+    fn import_stays_in_roots() {
+        let base = TempDir::new("playlist");
+        let root = base.join("root");
+        std::fs::create_dir_all(root.join("show")).unwrap();
+        std::fs::write(root.join("show/ep 1.mkv"), b"").unwrap();
+        std::fs::write(base.join("secret"), b"").unwrap();
+        let config = Config {
+            media_roots: vec![crate::config::MediaRoot { name: "Shows".into(), path: root.clone() }],
+            ..Config::default()
+        };
+        let items = parse(&format!(
+            "show/ep 1.mkv\nfile://{}/show/ep%201.mkv\n{}\n../secret\nhttps://example.com/a\nav://v4l2:/dev/video0\n",
+            root.display(), base.join("secret").display()));
+        let (accepted, skipped) = validate(&config, items, None);
+        let episode = root.join("show/ep 1.mkv").canonicalize().unwrap().to_string_lossy().into_owned();
+        assert_eq!(accepted, vec![episode.clone(), episode, "https://example.com/a".into()]);
+        assert_eq!(skipped.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn read_only_playlists() {
+        let dir = TempDir::new("playlist-file");
+        std::fs::write(dir.join("list.m3u"), "#EXTM3U\na.mkv\n").unwrap();
+        std::fs::write(dir.join("movie.mkv"), "#EXTM3U\na.mkv\n").unwrap();
+        std::fs::write(dir.join("big.m3u8"), "a.mkv\n".repeat(100)).unwrap();
+        assert_eq!(read_file(&dir.join("list.m3u"), 1024).await.unwrap(), "#EXTM3U\na.mkv\n");
+        assert!(read_file(&dir.join("movie.mkv"), 1024).await.is_err());
+        assert!(read_file(&dir.join("big.m3u8"), 100).await.is_err());
+    }
+}
@@ -1,135 +1,189 @@
-use std::path::Path;
-use std::env::current_dir;
+use std::net::IpAddr;
 use tokio::sync::broadcast;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
-use serde_json::{json, Value};
+use serde_json::{json, Map, Value};
 use serde::{Serialize, Deserialize};
 use anyhow::{Result, anyhow, bail};
-use rand::{self, rng, Rng};
 
+use crate::config::Config;
+use crate::{limits, media, metrics, playlist};
+use crate::logger::warning;
 use crate::mpv::{self, ObservedPropID, Event, Property, CmdHandle};
-use crate::websocket::WebSocketServer;
+use crate::websocket::{Message, WebSocketServer};
+
+/// close code sent to websocket clients over their command rate limit, "policy violation"
+const RATE_LIMITED: u16 = 1008;
+/// close code sent to websocket clients when mpv quits
+const GOING_AWAY: u16 = 1001;
 
   
 #[derive(Debug, Serialize, Deserialize)]
-struct WebEvent {
-    event: String,
-    data: Option<Value>,
+pub struct WebEvent {
+    pub event: String,
+    pub data: Option<Value>,
 }
 
 pub async fn handle_client_connection<T>(
     mut ws: WebSocketServer<T>, 
     cmd_handle: &mut CmdHandle<'_>, 
-    mut event_chan: broadcast::Receiver<Event>) -> Result<()>
+    mut event_chan: broadcast::Receiver<Event>,
+    config: &Config,
+    peer: Option<IpAddr>) -> Result<()>
 where
     T: AsyncRead + AsyncWrite + Unpin
 {
     let mut msg_buffer = String::new();
+    let bucket = limits::SocketBucket::new();
     loop {
         msg_buffer.clear();
         tokio::select! {
-            // wait a random time between 9-11 seconds
-            _time_sync_sleep = tokio::time::sleep(std::time::Duration::from_millis(9_000 + rand::random_range(0..=2000))) => {
-                let time_pos = match cmd_handle.get_property::<f64>("time-pos") {
-                    Ok(time) => time,
-                    Err(_) => continue,
-                };
-                let payload = json!({
-                    "event": "time-pos",
-                    "data": time_pos,
-                });
+            _time_sync_sleep = tokio::time::sleep(time_sync_interval()) => {
+                let Some(payload) = time_pos_payload(cmd_handle) else { continue };
                 let payload_str = serde_json::to_string(&payload)?;
                 ws.send_message(payload_str.as_str().into()).await?;
             }
             mpv_msg = event_chan.recv() => {
-                let mpv_msg = mpv_msg?;
-                match mpv_msg {
-                    Event::PropertyChange(property) => {
-                        match property {
-                            mpv::Property::Pause(val) => {
-                                let payload = json!({
-                                    "event": property.name(),
-                                    "data": val,
-                                });
-                                let payload_str = serde_json::to_string(&payload)?;
-                                ws.send_message(payload_str.as_str().into()).await?;
-                            },
-                            Property::Fullscreen(val) => {
-                                let payload = json!({
-                                    "event": property.name(),
-                                    "data": val,
-                                });
-                                let payload_str = serde_json::to_string(&payload)?;
-                                ws.send_message(payload_str.as_str().into()).await?;
-                            },
-                            Property::Playlist(ref val) => {
-                                let payload = json!({
-                                    "event": property.name(),
-                                    "data": val,
-                                });
-                                let payload_str = serde_json::to_string(&payload)?;
-                                ws.send_message(payload_str.as_str().into()).await?;
-                            },
-                            Property::CoreIdle(val) => {
-                                let payload = json!({
-                                    "event": property.name(),
-                                    "data": val,
-                                });
-                                let payload_str = serde_json::to_string(&payload)?;
-                                ws.send_message(payload_str.as_str().into()).await?;
-                            },
-                            _ => (),
-                        }
-                    },
-                    Event::FileLoaded => {
-                        let stat = cmd_handle.status();
-                        let response = json!({
-                            "event": "status",
-                            "data": stat
-                        });
-                        let msg = mpv::unwrap_or_continue!(serde_json::to_string(&response));
-                        ws.send_message(msg.as_str().into()).await?;
-                    },
-                    Event::EndFile => {
-                        ws.send_message("{\"event\": \"pause\", \"data\": true}".into()).await?;
+                let mpv_msg = match mpv_msg {
+                    Ok(Event::Shutdown) | Err(broadcast::error::RecvError::Closed) => {
+                        ws.send_message(Message::close(GOING_AWAY, "mpv is shutting down")).await?;
+                        return Ok(());
                     },
-                    Event::Seek => {
-                        let time_pos = match cmd_handle.get_property::<f64>("time-pos") {
-                            Ok(time) => time,
-                            Err(_) => continue,
-                        };
-                        let payload = json!({
-                            "event": "time-pos",
-                            "data": time_pos,
-                        });
-                        let payload_str = serde_json::to_string(&payload)?;
-                        ws.send_message(payload_str.as_str().into()).await?;
+                    Ok(mpv_msg) => mpv_msg,
+                    Err(broadcast::error::RecvError::Lagged(n)) => {
+                        metrics::lagged(n);
+                        continue;
                     },
-                    _ => ()
                 };
+                let Some(payload) = event_payload(&mpv_msg, cmd_handle) else { continue };
+                let msg = mpv::unwrap_or_continue!(serde_json::to_string(&payload));
+                ws.send_message(msg.as_str().into()).await?;
             },
             client_msg = ws.get_message() => {
                 let mut client_msg = client_msg?;
                 let _ = mpv::unwrap_or_continue!(client_msg.read_to_string(&mut msg_buffer).await);
                 let msg: WebEvent = mpv::unwrap_or_continue!(serde_json::from_str(msg_buffer.as_str()));
-                handle_webclient(msg, cmd_handle, &mut ws).await?;
+                // reading the status does not count against the limit, as with the http api
+                if msg.event != "get-status" && !limits::command(config, bucket.client()) {
+                    warning!("{peer:?} is sending commands too fast, closing its websocket");
+                    ws.send_message(Message::close(RATE_LIMITED, "too many commands")).await?;
+                    return Ok(());
+                }
+                handle_webclient(msg, cmd_handle, &mut ws, config).await?;
             },
         }
     }
 }
 
-async fn handle_webclient<T>(payload: WebEvent, handle: &mut CmdHandle<'_>, ws: &mut WebSocketServer<T>) -> Result<()>
+/// wait a random time between 9-11 seconds between pushing the playback position to clients
+pub fn time_sync_interval() -> std::time::Duration {
+    std::time::Duration::from_millis(9_000 + rand::random_range(0..=2000))
+}
+
+/// `time-pos` message keeping a client's playback position in sync, None when nothing is playing
+pub fn time_pos_payload(cmd_handle: &mut CmdHandle<'_>) -> Option<Value> {
+    let time_pos = cmd_handle.get_property::<f64>("time-pos").ok()?;
+    Some(json!({
+        "event": "time-pos",
+        "data": time_pos,
+    }))
+}
+
+/// The message pushed to clients for an mpv event, None for events clients are not told about.
+pub fn event_payload(event: &Event, cmd_handle: &mut CmdHandle<'_>) -> Option<Value> {
+    match event {
+        Event::PropertyChange(property) => {
+            let data = match property {
+                Property::Pause(val) | Property::Fullscreen(val) | Property::CoreIdle(val) => json!(val),
+                Property::Playlist(val) => val.clone(),
+                _ => return None,
+            };
+            Some(json!({
+                "event": property.name(),
+                "data": data,
+            }))
+        },
+        Event::FileLoaded => Some(json!({
+            "event": "status",
+            "data": cmd_handle.status(),
+        })),
+        Event::EndFile => Some(json!({
+            "event": "pause",
+            "data": true,
+        })),
+        Event::Seek => time_pos_payload(cmd_handle),
+        _ => None,
+    }
+}
+
+async fn handle_webclient<T>(payload: WebEvent, handle: &mut CmdHandle<'_>, ws: &mut WebSocketServer<T>, config: &Config) -> Result<()>
 where
     T: AsyncRead + AsyncWrite + Unpin
 {
+    match execute(payload, handle, config).await {
+        Ok(Some(reply)) => {
+            ws.send_message(serde_json::to_string(&reply)?.as_str().into()).await?;
+        },
+        Ok(None) => (),
+        Err(e) => crate::logger::error!("{e}"),
+    }
+    Ok(())
+}
+
+/// Adds a subtitle file to the current video. `flag` is `select` to show it right away, `auto` to
+/// leave track selection to mpv, or `cached` to reuse a track already added from the same file.
+pub fn sub_add(handle: &mut CmdHandle<'_>, path: &std::path::Path, flag: &str) -> Result<()> {
+    check_sub_flag(flag)?;
+    if media::Kind::from_path(path) != media::Kind::Subtitle {
+        bail!("{path:?} is not a subtitle file");
+    }
+    let path = path.to_str().ok_or(anyhow!("path is not utf-8"))?;
+    handle.command(["sub-add", path, flag]).map_err(|e| anyhow!(e))?;
+    Ok(())
+}
+
+/// `sub-add` flags clients may pass: select the new track, let mpv decide, or only add it
+pub fn check_sub_flag(flag: &str) -> Result<()> {
+    if !matches!(flag, "select" | "auto" | "cached") {
+        bail!("invalid subtitle flag \"{flag}\"");
+    }
+    Ok(())
+}
+
+/// The file picker subtitle of a `sub-add` message, resolved within the media roots, and its flag.
+fn sub_add_target<'a>(config: &Config, data: &'a Map<String, Value>) -> Result<(std::path::PathBuf, &'a str)> {
+    let file = data.get("file").and_then(|f| f.as_object()).ok_or(anyhow!("subtitle file not found"))?;
+    let dir = file.get("dir").and_then(|d| d.as_str()).ok_or(anyhow!("directory not found"))?;
+    let name = file.get("name").and_then(|n| n.as_str()).ok_or(anyhow!("file name not found"))?;
+    let flag = data.get("flag").and_then(|f| f.as_str()).unwrap_or("select");
+    check_sub_flag(flag)?;
+    let path = media::resolve(config, &format!("{dir}/{name}"))?;
+    if media::Kind::from_path(&path) != media::Kind::Subtitle {
+        bail!("{path:?} is not a subtitle file");
+    }
+    Ok((path, flag))
+}
+
+/// Runs a client command against mpv. Returns the event to send back to the client, if the command
+/// has one.
+pub async fn execute(payload: WebEvent, handle: &mut CmdHandle<'_>, config: &Config) -> Result<Option<WebEvent>> {
+    let event = payload.event.clone();
+    let result = run_command(payload, handle, config).await;
+    match result {
+        Ok(_) => metrics::command(&event),
+        Err(_) => metrics::command_error(),
+    }
+    result
+}
+
+async fn run_command(payload: WebEvent, handle: &mut CmdHandle<'_>, config: &Config) -> Result<Option<WebEvent>> {
     match payload.event.as_str() {
         "toggle-play" => {
             let paused: bool = handle.get_property("pause").map_err(|e| { anyhow!(e) })?;
-            handle.set_property("pause", !paused);
+            handle.set_property("pause", !paused).map_err(|e| { anyhow!(e) })?;
         },
         "toggle-fullscreen" => {
             let fullscreen: bool = handle.get_property("fullscreen").map_err(|e| { anyhow!(e) })?;
-            handle.set_property("fullscreen", !fullscreen);
+            handle.set_property("fullscreen", !fullscreen).map_err(|e| { anyhow!(e) })?;
         },
         "volume" => {
             let vol = match payload.data {
@@ -141,15 +195,13 @@ where
             let _ = handle.observe_property::<i64>(
                 ObservedPropID::Volume as u64,
                 ObservedPropID::Volume.to_string());
-            handle.set_property("ao-volume", vol);
+            handle.set_property("ao-volume", vol).map_err(|e| { anyhow!(e) })?;
         },
         "get-status" => {
-            let stat =  handle.status();
-            let response = json!({
-                "event": "status",
-                "data": stat
-            });
-            ws.send_message(serde_json::to_string(&response).unwrap().as_str().into()).await?;
+            return Ok(Some(WebEvent {
+                event: "status".into(),
+                data: Some(handle.status()),
+            }));
             },
         "seek" => {
             let data = match payload.data {
@@ -157,9 +209,9 @@ where
                 _ => bail!("seek data not found in message \"{payload:?}\""),
             };
             if let Some(Value::Number(n)) = data.get("relative") {
-                handle.command(["seek", format!("{}", n).as_str(), "relative"]);
+                handle.command(["seek", format!("{}", n).as_str(), "relative"]).map_err(|e| { anyhow!(e) })?;
             } else if let Some(Value::Number(n)) = data.get("absolute") {
-                handle.command(["seek", format!("{}", n).as_str(), "absolute"]);
+                handle.command(["seek", format!("{}", n).as_str(), "absolute"]).map_err(|e| { anyhow!(e) })?;
             } else {
                 bail!("seek data not found in message \"{payload:?}\"");
             }
@@ -169,7 +221,7 @@ where
                 Some(Value::String(n)) => n,
                 _ => bail!("skip data not found in message \"{payload:?}\""),
             };
-            handle.command([format!("playlist-{data}")]);
+            handle.command([format!("playlist-{data}")]).map_err(|e| { anyhow!(e) })?;
         },
         "play-now" => {
             let data = match payload.data {
@@ -177,15 +229,14 @@ where
                 _ => bail!("play-now data not found in message \"{payload:?}\""),
             };
             if let Some(Value::String(url)) = data.get("url") {
-                handle.command(["loadfile", url, "replace"]);
-            } 
+                let url = media::check_location(config, url, None).map_err(|e| anyhow!("refusing to load \"{url}\": {e}"))?;
+                handle.command(["loadfile", &url, "replace"]).map_err(|e| { anyhow!(e) })?;
+            }
             if let Some(Value::Object(file)) = data.get("file") {
-                let dir = file.get("dir").ok_or(anyhow!("directory not found"))?;
-                let name = file.get("name").ok_or(anyhow!("file name not found"))?;
-                let mut path = current_dir()?;
-                path.push(Path::new(dir.as_str().ok_or(anyhow!(""))?));
-                path.push(Path::new(name.as_str().ok_or(anyhow!(""))?));
-                handle.command(["loadfile", path.to_str().ok_or(anyhow!(""))?, "replace"]);
+                let dir = file.get("dir").and_then(|d| d.as_str()).ok_or(anyhow!("directory not found"))?;
+                let name = file.get("name").and_then(|n| n.as_str()).ok_or(anyhow!("file name not found"))?;
+                let path = media::resolve(config, &format!("{dir}/{name}"))?;
+                handle.command(["loadfile", path.to_str().ok_or(anyhow!("path is not utf-8"))?, "replace"]).map_err(|e| { anyhow!(e) })?;
             }
         },
         "playlist-add" => {
@@ -194,27 +245,60 @@ where
                 _ => bail!("playist-add data not found in message \"{payload:?}\""),
             };
             if let Some(Value::String(url)) = data.get("url") {
-                handle.command(["loadfile", url, "append-play"]);
-            } 
+                let url = media::check_location(config, url, None).map_err(|e| anyhow!("refusing to load \"{url}\": {e}"))?;
+                handle.command(["loadfile", &url, "append-play"]).map_err(|e| { anyhow!(e) })?;
+            }
             if let Some(Value::Object(file)) = data.get("file") {
-                let dir = file.get("dir").ok_or(anyhow!("directory not found"))?;
-                let name = file.get("name").ok_or(anyhow!("file name not found"))?;
-                let mut path = current_dir()?;
-                path.push(Path::new(dir.as_str().ok_or(anyhow!(""))?));
-                path.push(Path::new(name.as_str().ok_or(anyhow!(""))?));
-                handle.command(["loadfile", path.to_str().ok_or(anyhow!(""))?, "append-play"]);
+                let dir = file.get("dir").and_then(|d| d.as_str()).ok_or(anyhow!("directory not found"))?;
+                let name = file.get("name").and_then(|n| n.as_str()).ok_or(anyhow!("file name not found"))?;
+                let path = media::resolve(config, &format!("{dir}/{name}"))?;
+                handle.command(["loadfile", path.to_str().ok_or(anyhow!("path is not utf-8"))?, "append-play"]).map_err(|e| { anyhow!(e) })?;
             }
         },
+        "playlist-import" => {
+            let data = match payload.data {
+                Some(Value::Object(v)) => v,
+                _ => bail!("playlist-import data not found in message \"{payload:?}\""),
+            };
+            let mode = match data.get("mode").and_then(|m| m.as_str()) {
+                Some(mode) => playlist::Mode::from_name(mode).ok_or(anyhow!("unknown import mode \"{mode}\""))?,
+                None => playlist::Mode::AppendPlay,
+            };
+            let report = if let Some(Value::String(document)) = data.get("document") {
+                playlist::import(config, handle, document, None, mode)?
+            } else if let Some(Value::Object(file)) = data.get("file") {
+                // a playlist from the file picker, its relative entries are next to it
+                let dir = file.get("dir").and_then(|d| d.as_str()).ok_or(anyhow!("directory not found"))?;
+                let name = file.get("name").and_then(|n| n.as_str()).ok_or(anyhow!("file name not found"))?;
+                let path = media::resolve(config, &format!("{dir}/{name}"))?;
+                let document = playlist::read_file(&path, config.max_body_size).await?;
+                playlist::import(config, handle, &document, path.parent(), mode)?
+            } else {
+                bail!("playlist-import expects a \"document\" or a \"file\"");
+            };
+            return Ok(Some(WebEvent {
+                event: "playlist-import".into(),
+                data: Some(report),
+            }));
+        },
+        "sub-add" => {
+            let data = match payload.data {
+                Some(Value::Object(v)) => v,
+                _ => bail!("sub-add data not found in message \"{payload:?}\""),
+            };
+            let (path, flag) = sub_add_target(config, &data)?;
+            sub_add(handle, &path, flag)?;
+        },
         "playlist-remove" => {
             let idx = match payload.data {
-                Some(Value::Number(n)) => n.as_i64().unwrap(),
+                Some(Value::Number(ref n)) => n.as_i64().ok_or(anyhow!("unable to parse integer"))?,
                 _ => bail!("playist-remove data not found in message \"{payload:?}\""),
             };
-            handle.command(["playlist-remove", &format!("{idx}")]);
+            handle.command(["playlist-remove", &format!("{idx}")]).map_err(|e| { anyhow!(e) })?;
         },
         "playlist-move" => {
             let ids = match payload.data {
-                Some(Value::Array(arr)) => arr,
+                Some(Value::Array(arr)) if arr.len() == 2 => arr,
                 _ => bail!("playist-move data not found in message \"{payload:?}\""),
             };
             let id_1 = ids[0].as_i64().ok_or(anyhow!("unable to parse integer"))?;
@@ -222,16 +306,58 @@ where
             if id_1 < id_2 {
                 id_2 += 1;
             }
-            handle.command(["playlist-move", &format!("{id_1}"), &format!("{id_2}")]);
+            handle.command(["playlist-move", &format!("{id_1}"), &format!("{id_2}")]).map_err(|e| { anyhow!(e) })?;
         },
         "shutdown" => {
-            handle.command(["quit"]);
+            handle.command(["quit"]).map_err(|e| { anyhow!(e) })?;
         },
         "stop" => {
-            handle.command(["write-watch-later-config"]);
-            handle.command(["stop"]);
+            let _ = handle.command(["write-watch-later-config"]);
+            handle.command(["stop"]).map_err(|e| { anyhow!(e) })?;
         },
-        _ => (),
+        event => bail!("unknown command \"{event}\""),
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::MediaRoot;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn sub_flags() {
+        for flag in ["select", "auto", "cached"] {
+            assert!(check_sub_flag(flag).is_ok());
+        }
+        assert!(check_sub_flag("SELECT").is_err());
+        assert!(check_sub_flag("select,auto").is_err());
+        assert!(check_sub_flag("").is_err());
+    }
+
+    #[test]
+    fn sub_add_paths() {
+        let base = TempDir::new("sub-add");
+        std::fs::create_dir_all(base.join("Movies")).unwrap();
+        std::fs::write(base.join("Movies/a.srt"), b"").unwrap();
+        std::fs::write(base.join("Movies/a.mkv"), b"").unwrap();
+        std::fs::write(base.join("outside.srt"), b"").unwrap();
+        let config = Config {
+            media_roots: vec![MediaRoot { name: "Movies".into(), path: base.join("Movies") }],
+            ..Config::default()
+        };
+        let data = |dir: &str, name: &str, flag: &str| json!({ "file": { "dir": dir, "name": name }, "flag": flag })
+            .as_object().unwrap().clone();
+
+        let subtitle = data("Movies", "a.srt", "auto");
+        let (path, flag) = sub_add_target(&config, &subtitle).unwrap();
+        assert_eq!((path, flag), (base.join("Movies/a.srt"), "auto"));
+        // only subtitles inside a media root, with a known flag
+        assert!(sub_add_target(&config, &data("Movies", "../outside.srt", "select")).is_err());
+        assert!(sub_add_target(&config, &data(base.to_str().unwrap(), "outside.srt", "select")).is_err());
+        assert!(sub_add_target(&config, &data("Movies/..", "outside.srt", "select")).is_err());
+        assert!(sub_add_target(&config, &data("Movies", "a.mkv", "select")).is_err());
+        assert!(sub_add_target(&config, &data("Movies", "a.srt", "replace")).is_err());
     }
-    Ok(())
 }
@@ -0,0 +1,225 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use serde_json::{json, Value};
+use tokio::io::AsyncWrite;
+use anyhow::Result;
+use crate::config::Config;
+use crate::image::Image;
+use crate::logger::warning;
+use crate::server::{Request, Response};
+
+/// sizes of the generated icons, the ones install prompts ask for
+const ICON_SIZES: &[usize] = &[192, 512];
+/// splash screen colour, the dark background of the UI
+const BACKGROUND_COLOR: &str = "#171717";
+/// used when `theme_color` cannot be parsed
+const DEFAULT_THEME_COLOR: [u8; 3] = [0x1f, 0x29, 0x37];
+/// paths the service worker keeps for offline start up, the rest always goes to the network
+const APP_SHELL: &[&str] = &[
+    "/",
+    "/static/main.js",
+    "/static/output.css",
+    "/static/symbols/material-symbols.woff2",
+    "/manifest.webmanifest",
+    "/icons/icon-192.png",
+    "/icons/icon-512.png",
+];
+
+// icons are drawn on first use, the theme colour cannot change while the plugin runs
+static ICONS: Mutex<BTreeMap<usize, Arc<Vec<u8>>>> = Mutex::new(BTreeMap::new());
+
+/// parses `#rrggbb` or `#rgb`
+fn parse_color(color: &str) -> Option<[u8; 3]> {
+    let hex = color.trim().strip_prefix('#')?;
+    // the channels are sliced by byte
+    if !hex.is_ascii() {
+        return None;
+    }
+    let channel = |s: &str| u8::from_str_radix(s, 16).ok();
+    match hex.len() {
+        6 => Some([channel(&hex[0 .. 2])?, channel(&hex[2 .. 4])?, channel(&hex[4 .. 6])?]),
+        3 => {
+            let short = |i: usize| channel(&hex[i .. i + 1]).map(|c| c * 17);
+            Some([short(0)?, short(1)?, short(2)?])
+        },
+        _ => None,
+    }
+}
+
+fn theme_color(config: &Config) -> [u8; 3] {
+    parse_color(&config.theme_color).unwrap_or_else(|| {
+        warning!("invalid theme_color \"{}\", expected #rrggbb", config.theme_color);
+        DEFAULT_THEME_COLOR
+    })
+}
+
+fn hex(color: [u8; 3]) -> String {
+    format!("#{:02x}{:02x}{:02x}", color[0], color[1], color[2])
+}
+
+/// The web app manifest: the instance name, the theme colour and the icons, opened full screen.
+pub fn manifest(config: &Config) -> Value {
+    let icons: Vec<Value> = ICON_SIZES.iter().map(|size| json!({
+        "src": format!("/icons/icon-{size}.png"),
+        "sizes": format!("{size}x{size}"),
+        "type": "image/png",
+        // the play symbol stays inside the safe zone, so the icons can be masked
+        "purpose": "any maskable",
+    })).collect();
+    json!({
+        "id": "/",
+        "name": config.instance_name(),
+        "short_name": "mpvRemote",
+        "description": "Remote control for mpv",
+        "start_url": "/",
+        "scope": "/",
+        "display": "fullscreen",
+        "display_override": ["fullscreen", "standalone"],
+        "theme_color": hex(theme_color(config)),
+        "background_color": BACKGROUND_COLOR,
+        "icons": icons,
+    })
+}
+
+/// A square icon of `size` pixels: a play symbol on the theme colour, filling the whole square as
+/// maskable icons must.
+pub fn icon(config: &Config, size: usize) -> Image {
+    let background = theme_color(config);
+    let s = size as f64;
+    // the triangle fits in the central 40% radius safe zone
+    let (left, width, height) = (s * 0.36, s * 0.34, s * 0.40);
+    let inside = |x: f64, y: f64| {
+        let along = (x - left) / width;
+        (0. ..= 1.).contains(&along) && (y - s / 2.).abs() <= height / 2. * (1. - along)
+    };
+    let mut rgb = Vec::with_capacity(size * size * 3);
+    for y in 0 .. size {
+        for x in 0 .. size {
+            // 4x4 samples per pixel for smooth edges
+            let covered = (0 .. 16)
+                .filter(|i| inside(x as f64 + (i % 4) as f64 / 4. + 0.125, y as f64 + (i / 4) as f64 / 4. + 0.125))
+                .count() as f64 / 16.;
+            rgb.extend(background.iter().map(|&c| (c as f64 + (255. - c as f64) * covered).round() as u8));
+        }
+    }
+    Image { width: size, height: size, rgb }
+}
+
+fn icon_png(config: &Config, size: usize) -> Result<Arc<Vec<u8>>> {
+    if let Some(png) = ICONS.lock().unwrap().get(&size) {
+        return Ok(png.clone());
+    }
+    let png = Arc::new(icon(config, size).png()?);
+    ICONS.lock().unwrap().insert(size, png.clone());
+    Ok(png)
+}
+
+/// The service worker. It keeps the app shell for starting without a connection, fetching it from the
+/// network first so updates show up right away. Everything else, the API, websocket, event stream and
+/// media, is never touched. The cache is named after the plugin version and theme, so changing either
+/// replaces it.
+pub fn service_worker(config: &Config) -> String {
+    let cache = format!("mpv-remote-{}-{}", env!("CARGO_PKG_VERSION"), hex(theme_color(config)).trim_start_matches('#'));
+    let shell = serde_json::to_string(APP_SHELL).unwrap_or_default();
+    format!(r#"const CACHE = "{cache}";
+const SHELL = {shell};
+
+self.addEventListener("install", (event) => {{
+    event.waitUntil(caches.open(CACHE).then((cache) => cache.addAll(SHELL)).then(() => self.skipWaiting()));
+}});
+
+self.addEventListener("activate", (event) => {{
+    event.waitUntil(caches.keys()
+        .then((keys) => Promise.all(keys.filter((key) => key !== CACHE).map((key) => caches.delete(key))))
+        .then(() => self.clients.claim()));
+}});
+
+self.addEventListener("fetch", (event) => {{
+    const url = new URL(event.request.url);
+    if (event.request.method !== "GET" || url.origin !== self.location.origin || !SHELL.includes(url.pathname)) return;
+    event.respondWith(fetch(event.request).then((response) => {{
+        // the login page is answered with 401 and never kept
+        if (response.ok) {{
+            const copy = response.clone();
+            caches.open(CACHE).then((cache) => cache.put(event.request, copy));
+        }}
+        return response;
+    }}).catch(() => caches.match(event.request).then((cached) => cached || Response.error())));
+}});
+"#)
+}
+
+async fn not_found<T>(request: &Request, stream: &mut T) -> Result<()>
+where
+    T: AsyncWrite + Unpin,
+{
+    warning!("no such app resource \"{}\"", request.path);
+    Response::new("HTTP/1.1", 404).write_to(stream).await
+}
+
+/// Serves `/manifest.webmanifest`, `/icons/icon-<size>.png` and `/sw.js`.
+pub async fn handle<T>(request: &Request, url: &[&str], stream: &mut T, config: &Config) -> Result<()>
+where
+    T: AsyncWrite + Unpin,
+{
+    match url {
+        ["manifest.webmanifest"] => {
+            let manifest = manifest(config).to_string();
+            Response::new("HTTP/1.1", 200)
+                .header("Content-Type", "application/manifest+json")
+                .header("Cache-Control", "no-cache")
+                .body(manifest.as_bytes())
+                .write_to(stream).await
+        },
+        ["icons", name] => {
+            let size = name.strip_prefix("icon-")
+                .and_then(|n| n.strip_suffix(".png"))
+                .and_then(|n| n.parse::<usize>().ok())
+                .filter(|size| ICON_SIZES.contains(size));
+            let Some(size) = size else { return not_found(request, stream).await };
+            let png = icon_png(config, size)?;
+            Response::new("HTTP/1.1", 200)
+                .header("Content-Type", "image/png")
+                .header("Cache-Control", "max-age=86400")
+                .body(&png[..])
+                .write_to(stream).await
+        },
+        ["sw.js"] => {
+            let script = service_worker(config);
+            // browsers check for a new worker on every navigation, it must not be cached
+            Response::new("HTTP/1.1", 200)
+                .header("Content-Type", "text/javascript")
+                .header("Cache-Control", "no-cache")
+                .header("Service-Worker-Allowed", "/")
+                .body(script.as_bytes())
+                .write_to(stream).await
+        },
+        _ => not_found(request, stream).await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manifest_from_config() {
+        assert_eq!(parse_color("#0a0B0c"), Some([10, 11, 12]));
+        assert_eq!(parse_color("#fff"), Some([255, 255, 255]));
+        assert_eq!(parse_color("teal"), None);
+        assert_eq!(parse_color("#ééé"), None);
+        assert_eq!(parse_color("#aébbb"), None);
+        assert_eq!(parse_color("#éa"), None);
+        let config = Config { name: Some("Living room".into()), theme_color: "#123".into(), ..Config::default() };
+        let manifest = manifest(&config);
+        assert_eq!(manifest["name"], "Living room");
+        assert_eq!(manifest["theme_color"], "#112233");
+        assert_eq!(manifest["icons"].as_array().unwrap().len(), ICON_SIZES.len());
+        let icon = icon(&config, 192);
+        assert_eq!(icon.rgb.len(), 192 * 192 * 3);
+        // background in the corner, the play symbol in the middle
+        assert_eq!(&icon.rgb[.. 3], &[0x11, 0x22, 0x33]);
+        let centre = (96 * 192 + 96) * 3;
+        assert_eq!(&icon.rgb[centre .. centre + 3], &[255, 255, 255]);
+    }
+}
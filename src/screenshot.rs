@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+use mpv_client::Node;
+use tokio::io::AsyncWrite;
+use anyhow::{Result, anyhow, bail};
+use crate::image::Image;
+use crate::logger::warning;
+use crate::mpv::CmdHandle;
+use crate::server::{Method, Request, Response, json_error};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    Png,
+    Jpeg,
+}
+
+/// options of `GET /api/screenshot`
+#[derive(Debug)]
+struct Options {
+    format: Format,
+    quality: u8,
+    width: Option<usize>,
+    height: Option<usize>,
+    subtitles: bool,
+    osd: bool,
+}
+
+fn flag(query: &HashMap<String, String>, key: &str, default: bool) -> Result<bool> {
+    match query.get(key).map(|v| v.as_str()) {
+        None => Ok(default),
+        Some("1" | "true" | "yes") => Ok(true),
+        Some("0" | "false" | "no") => Ok(false),
+        Some(v) => bail!("\"{key}\" should be true or false, got \"{v}\""),
+    }
+}
+
+fn size(query: &HashMap<String, String>, key: &str) -> Result<Option<usize>> {
+    match query.get(key) {
+        Some(n) => match n.parse() {
+            Ok(n) if n > 0 => Ok(Some(n)),
+            _ => bail!("\"{key}\" should be a positive integer, got \"{n}\""),
+        },
+        None => Ok(None),
+    }
+}
+
+impl Options {
+    fn from_query(query: &HashMap<String, String>) -> Result<Self> {
+        let format = match query.get("format").map(|f| f.as_str()) {
+            None | Some("png") => Format::Png,
+            Some("jpeg" | "jpg") => Format::Jpeg,
+            Some(f) => bail!("unknown format \"{f}\", expected png or jpeg"),
+        };
+        let quality = match query.get("quality") {
+            Some(q) => q.parse().ok().filter(|q| (1 ..= 100).contains(q))
+                .ok_or(anyhow!("\"quality\" should be between 1 and 100, got \"{q}\""))?,
+            None => 85,
+        };
+        Ok(Options {
+            format,
+            quality,
+            width: size(query, "width")?,
+            height: size(query, "height")?,
+            subtitles: flag(query, "subs", true)?,
+            osd: flag(query, "osd", false)?,
+        })
+    }
+
+    /// the `screenshot-raw` flag: "window" is the only one that includes the OSD, and it always
+    /// includes subtitles
+    fn mpv_flag(&self) -> &'static str {
+        match (self.osd, self.subtitles) {
+            (true, _) => "window",
+            (false, true) => "subtitles",
+            (false, false) => "video",
+        }
+    }
+}
+
+/// Takes a screenshot with `screenshot-raw`, which returns a map describing the frame's pixels.
+/// `flag` is one of "video", "subtitles" or "window".
+pub fn capture(cmd_handle: &mut CmdHandle<'_>, flag: &str) -> Result<Image> {
+    let Node::Map(mut frame) = cmd_handle.command_ret(["screenshot-raw", flag])? else {
+        bail!("nothing to take a screenshot of");
+    };
+    let int = |frame: &HashMap<String, Node>, key: &str| match frame.get(key) {
+        Some(Node::Int(n)) if *n >= 0 => Ok(*n as usize),
+        _ => Err(anyhow!("screenshot is missing \"{key}\"")),
+    };
+    let (width, height, stride) = (int(&frame, "w")?, int(&frame, "h")?, int(&frame, "stride")?);
+    let format = match frame.get("format") {
+        Some(Node::String(f)) => f.clone(),
+        _ => "bgr0".into(),
+    };
+    let Some(Node::ByteArray(data)) = frame.remove("data") else {
+        bail!("screenshot is missing its pixel data");
+    };
+    Image::from_raw(width, height, stride, &format, &data)
+}
+
+/// `GET /api/screenshot?format=png|jpeg&quality=&width=&height=&subs=&osd=`: the current frame,
+/// scaled down to fit `width` and `height` when given.
+pub async fn handle<T>(request: &Request, stream: &mut T, cmd_handle: &mut CmdHandle<'_>) -> Result<()>
+where
+    T: AsyncWrite + Unpin,
+{
+    let result = async {
+        if request.method != Method::GET {
+            return Err((405, format!("expected GET got {:?}", request.method)));
+        }
+        let options = Options::from_query(&request.query()).map_err(|e| (400, e.to_string()))?;
+        let image = capture(cmd_handle, options.mpv_flag()).map_err(|e| (409, e.to_string()))?;
+        // encoding a full size frame takes a while, keep it off the runtime's worker threads
+        tokio::task::spawn_blocking(move || {
+            let (width, height) = image.fit(options.width, options.height);
+            let image = image.resize(width, height);
+            match options.format {
+                Format::Png => image.png().map(|data| ("image/png", data)),
+                Format::Jpeg => image.jpeg(options.quality).map(|data| ("image/jpeg", data)),
+            }
+        }).await.map_err(|e| (500, e.to_string()))?.map_err(|e| (500, e.to_string()))
+    }.await;
+    match result {
+        Ok((mime, data)) => {
+            let response = Response::new("HTTP/1.1", 200)
+                .headers(&request.response_headers)
+                .header("Content-Type", mime)
+                .header("Cache-Control", "no-store")
+                .body(&data);
+            response.write_to(stream).await?;
+        },
+        Err((status, error)) => {
+            warning!("screenshot: {error}");
+            json_error(request, stream, status, &error).await?;
+        },
+    }
+    Ok(())
+}
@@ -1,21 +1,27 @@
 use core::str;
+use std::borrow::Cow;
+use std::cell::Cell;
 use std::collections::HashMap;
 use std::fmt::{Debug, Display};
 use std::path;
+use std::sync::Arc;
+use std::time::Duration;
 use serde_json::json;
-use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use socket2::{Domain, Protocol, Socket, Type};
 use tokio::net::TcpListener;
-use tokio::fs;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::task::JoinSet;
+use tokio_rustls::TlsAcceptor;
 use anyhow::{Result, anyhow};
-use crate::logger::{debug, warning};
-use crate::{logger, plugin, websocket};
+use crate::logger::{debug, error, info, warning};
+use crate::{api, artwork, assets, auth, cors, discovery, limits, logger, media, metrics, mpv, playlist, plugin, pwa, screenshot, sse, stream, tls, upload, websocket};
+use crate::config::{Config, Endpoint};
 use crate::mpv::{CmdHandle, EventSubscriber};
 
 
-const INDEX_HTML: &[u8] = include_bytes!("../www/index.html");
-const MAIN_JS: &[u8] = include_bytes!("../www/static/main.js");
-const OUTPUT_CSS: &[u8] = include_bytes!("../www/static/output.css");
-const SYMBOLS_FONT: &[u8] = include_bytes!("../www/static/symbols/material-symbols.woff2");
 
 macro_rules! continue_on_err {
     ($expression:expr) => {
@@ -32,20 +38,58 @@ pub fn parse_url<'a>(url: &'a str) -> Url<'a> {
     url.split("/").collect()
 }
 
+/// parses `application/x-www-form-urlencoded` data, as used by query strings and form bodies
+pub fn parse_query(query: &str) -> HashMap<String, String> {
+    query.split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, val) = pair.split_once('=').unwrap_or((pair, ""));
+            (percent_decode(&key.replace('+', " ")), percent_decode(&val.replace('+', " ")))
+        })
+        .collect()
+}
+
+/// decodes %XX escapes, invalid escapes are kept as is
+pub fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = match bytes.get(i + 1 .. i + 3) {
+            Some(hex) if bytes[i] == b'%' => str::from_utf8(hex).ok().and_then(|h| u8::from_str_radix(h, 16).ok()),
+            _ => None,
+        };
+        match escaped {
+            Some(b) => {
+                out.push(b);
+                i += 3;
+            },
+            None => {
+                out.push(bytes[i]);
+                i += 1;
+            },
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
 #[derive(Debug)]
 pub struct Request {
     pub method: Method,
     pub path: String,
     pub ver: String,
     pub headers: HashMap<String, String>,
+    /// address of the client, None for unix socket connections
+    pub peer: Option<IpAddr>,
+    /// headers every response to this request carries, such as CORS headers
+    pub response_headers: Vec<(String, String)>,
 }
 
 impl Request {
-    async fn parse<T>(stream: &mut T) -> Result<Request> 
+    pub(crate) async fn parse<T>(lines: &mut T) -> Result<Request> 
     where 
-    T: AsyncRead + Unpin,
+    T: AsyncBufRead + Unpin,
     {
-        let mut lines = BufReader::new(stream);
         let mut buf = String::new();
         let first_line  = match lines.read_line(&mut buf).await {
             Ok(0) => { return Err(anyhow!("unexpected EOF in http request")) },
@@ -75,11 +119,283 @@ impl Request {
             path: path.to_owned(), 
             ver: ver.to_owned(),
             headers,
+            peer: None,
+            response_headers: Vec::new(),
         })
     }
+
+    /// request path without the query string
+    pub fn route(&self) -> &str {
+        self.path.split_once('?').map_or(self.path.as_str(), |(route, _)| route)
+    }
+
+    /// decoded query string parameters
+    pub fn query(&self) -> HashMap<String, String> {
+        match self.path.split_once('?') {
+            Some((_, query)) => parse_query(query),
+            None => HashMap::new(),
+        }
+    }
+
+    /// header lookup ignoring the case of the header name
+    pub fn header(&self, key: &str) -> Option<&String> {
+        self.headers.iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, v)| v)
+    }
+
+    /// whether the client wants the connection kept open after the response
+    pub fn keep_alive(&self) -> bool {
+        let connection = self.header("Connection").map(|c| c.to_ascii_lowercase());
+        match connection.as_deref() {
+            Some(c) if c.contains("close") => false,
+            Some(c) if c.contains("keep-alive") => true,
+            _ => self.ver == "HTTP/1.1",
+        }
+    }
+
+    pub fn has_body(&self) -> bool {
+        self.header("Transfer-Encoding").is_some()
+            || self.header("Content-Length").is_some_and(|len| len.trim() != "0")
+    }
+
+    /// framing of the body as declared by the headers, before any of it is read
+    fn body_state(&self) -> Result<BodyState> {
+        let chunked = self.header("Transfer-Encoding")
+            .is_some_and(|enc| enc.to_ascii_lowercase().contains("chunked"));
+        let (framing, remaining) = if chunked {
+            (Framing::Chunked, 0)
+        } else {
+            let len = match self.header("Content-Length") {
+                Some(len) => len.parse::<u64>().map_err(|_| anyhow!("invalid Content-Length \"{len}\""))?,
+                None => 0,
+            };
+            (Framing::Length, len)
+        };
+        Ok(BodyState { framing, remaining, total: 0, done: false, failed: false })
+    }
+
+    /// Body reader for this request over the stream the headers were parsed from. Fails with
+    /// `PayloadTooLarge` up front if the declared Content-Length is over `limit`. A reader made again
+    /// for the same request carries on where the previous one stopped.
+    pub fn body<'a, T>(&self, stream: &'a mut T, limit: u64) -> Result<Body<'a, T>>
+    where
+        T: AsyncBufRead + Unpin,
+    {
+        let state = self.body_state()?;
+        if state.framing == Framing::Length && state.remaining > limit {
+            return Err(PayloadTooLarge.into());
+        }
+        let state = EXCHANGE.try_with(|e| e.get().body).ok().flatten().unwrap_or(state);
+        Ok(Body { stream, state, limit })
+    }
 }
 
 #[derive(Debug)]
+pub struct PayloadTooLarge;
+
+impl Display for PayloadTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "request body too large")
+    }
+}
+
+impl std::error::Error for PayloadTooLarge {}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Framing {
+    Length,
+    Chunked,
+}
+
+/// How far a request body has been read.
+#[derive(Debug, Clone, Copy)]
+struct BodyState {
+    framing: Framing,
+    /// bytes left in the body, or in the current chunk when chunked
+    remaining: u64,
+    total: u64,
+    done: bool,
+    /// a read failed part way, where the next request starts is unknown
+    failed: bool,
+}
+
+impl BodyState {
+    /// whether the connection can take another request once at most `limit` unread bytes are skipped
+    fn reusable(&self, limit: u64) -> bool {
+        self.done || (!self.failed && self.framing == Framing::Length && self.remaining <= limit)
+    }
+}
+
+/// The request being answered on a connection, shared with `Body` and `Response` through a task
+/// local the way the metrics get the response status.
+#[derive(Debug, Clone, Copy)]
+struct Exchange {
+    /// the client asked for the connection to be closed after the response
+    close: bool,
+    /// a HEAD request, responses are sent with the headers a GET would get but no body
+    head: bool,
+    /// None for requests without a body
+    body: Option<BodyState>,
+    /// how much of a body left unread by the route is skipped to reuse the connection
+    drain_limit: u64,
+}
+
+impl Exchange {
+    fn new(request: &Request, drain_limit: u64) -> Exchange {
+        let body = request.has_body().then(|| request.body_state().unwrap_or(BodyState {
+            framing: Framing::Length,
+            remaining: 0,
+            total: 0,
+            done: false,
+            failed: true,
+        }));
+        Exchange { close: !request.keep_alive(), head: request.method == Method::HEAD, body, drain_limit }
+    }
+
+    /// whether the connection is closed after the response
+    fn closes(&self) -> bool {
+        self.close || self.body.is_some_and(|body| !body.reusable(self.drain_limit))
+    }
+}
+
+tokio::task_local! {
+    static EXCHANGE: Cell<Exchange>;
+}
+
+/// Request body decoded from Content-Length or chunked transfer encoding
+pub struct Body<'a, T> {
+    stream: &'a mut T,
+    state: BodyState,
+    limit: u64,
+}
+
+impl<'a, T: AsyncBufRead + Unpin> Body<'a, T> {
+    /// reads the size line of the next chunk, and the trailers after the last one
+    async fn next_chunk(&mut self) -> Result<()> {
+        let mut line = String::new();
+        if self.stream.read_line(&mut line).await? == 0 {
+            return Err(anyhow!("unexpected EOF in chunked body"));
+        }
+        let size = line.trim().split(';').next().unwrap_or("");
+        self.state.remaining = u64::from_str_radix(size.trim(), 16).map_err(|_| anyhow!("invalid chunk size \"{size}\""))?;
+        if self.state.remaining == 0 {
+            loop {
+                line.clear();
+                if self.stream.read_line(&mut line).await? == 0 || line.trim().is_empty() {
+                    break;
+                }
+            }
+            self.state.done = true;
+        }
+        Ok(())
+    }
+
+    /// Reads the next part of the body into `buf`, returns 0 at the end of the body.
+    pub async fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if self.state.failed {
+            return Err(anyhow!("request body could not be read"));
+        }
+        let n = self.read_part(buf).await;
+        self.state.failed = n.is_err();
+        // the connection reads the rest of the body from where this reader stopped
+        let _ = EXCHANGE.try_with(|e| e.set(Exchange { body: Some(self.state), ..e.get() }));
+        n
+    }
+
+    async fn read_part(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if self.state.done || buf.is_empty() {
+            return Ok(0);
+        }
+        if self.state.remaining == 0 {
+            match self.state.framing {
+                Framing::Length => {
+                    self.state.done = true;
+                    return Ok(0);
+                },
+                Framing::Chunked => {
+                    self.next_chunk().await?;
+                    if self.state.done {
+                        return Ok(0);
+                    }
+                },
+            }
+        }
+        let len = buf.len().min(self.state.remaining.try_into().unwrap_or(usize::MAX));
+        let n = self.stream.read(&mut buf[.. len]).await?;
+        if n == 0 {
+            return Err(anyhow!("unexpected EOF in request body"));
+        }
+        self.state.remaining -= n as u64;
+        self.state.total += n as u64;
+        if self.state.total > self.limit {
+            return Err(PayloadTooLarge.into());
+        }
+        if self.state.framing == Framing::Chunked && self.state.remaining == 0 {
+            let mut crlf = String::new();
+            self.stream.read_line(&mut crlf).await?;
+        }
+        Ok(n)
+    }
+
+    /// reads and drops the rest of the body
+    async fn drain(&mut self) -> Result<()> {
+        let mut buf = [0; 8192];
+        while self.read(&mut buf).await? > 0 {}
+        Ok(())
+    }
+
+    pub async fn read_to_end(&mut self) -> Result<Vec<u8>> {
+        let mut body = Vec::new();
+        let mut buf = [0; 8192];
+        loop {
+            match self.read(&mut buf).await? {
+                0 => return Ok(body),
+                n => body.extend_from_slice(&buf[.. n]),
+            }
+        }
+    }
+}
+
+/// Answers with `{"ok": false, "error": ...}`, the failure reply of every JSON route.
+pub async fn json_error<T>(request: &Request, stream: &mut T, status: u16, error: &str) -> Result<()>
+where
+    T: AsyncWrite + Unpin,
+{
+    let payload = json!({ "ok": false, "error": error }).to_string();
+    Response::new("HTTP/1.1", status)
+        .headers(&request.response_headers)
+        .header("Content-Type", "application/json")
+        .body(payload.as_bytes())
+        .write_to(stream).await
+}
+
+/// Reads the whole request body. Answers 413 and returns None if it is larger than `limit`.
+pub async fn read_body<T>(request: &Request, stream: &mut T, limit: u64) -> Result<Option<Vec<u8>>>
+where
+    T: AsyncBufRead + AsyncWrite + Unpin,
+{
+    let body = match request.body(stream, limit) {
+        Ok(mut body) => body.read_to_end().await,
+        Err(e) => Err(e),
+    };
+    match body {
+        Ok(body) => Ok(Some(body)),
+        Err(e) if e.is::<PayloadTooLarge>() => {
+            warning!("{e} for \"{}\"", request.path);
+            let response = Response::new("HTTP/1.1", 413)
+                .headers(&request.response_headers)
+                .header("Content-Type", "text/plain")
+                .header("Connection", "close")
+                .body(b"request body too large");
+            response.write_to(stream).await?;
+            Ok(None)
+        },
+        Err(e) => Err(e),
+    }
+}
+
+#[derive(Debug, PartialEq)]
 pub enum Method {
     GET,
     HEAD,
@@ -111,21 +427,61 @@ impl TryFrom<&str> for Method {
     }
 }
 
-#[derive(Debug)]
+/// A response body, held in memory or streamed from a reader.
+enum ResponseBody<'a> {
+    Empty,
+    Bytes(Cow<'a, [u8]>),
+    /// sent with a Content-Length when its length is known, chunked otherwise
+    Reader(Box<dyn AsyncRead + Send + Unpin + 'a>, Option<u64>),
+}
+
 pub struct Response<'a> {
     version: String,
     status: u16,
     headers: Vec<(String, String)>,
-    body: Option<&'a [u8]>,
+    body: ResponseBody<'a>,
 }
 
+/// standard reason phrase for a status code
+fn reason(status: u16) -> &'static str {
+    match status {
+        101 => "Switching Protocols",
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        206 => "Partial Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        412 => "Precondition Failed",
+        413 => "Content Too Large",
+        415 => "Unsupported Media Type",
+        416 => "Range Not Satisfiable",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        _ => "",
+    }
+}
+
+/// size of the chunks a reader body without a known length is sent in
+const CHUNK_SIZE: usize = 64 * 1024;
+
 impl<'a> Response<'a> {
     pub fn new(version: &str, status: u16) -> Response<'a> {
         Response {
             version: version.into(), 
             status, 
             headers: Vec::new(),
-            body: None
+            body: ResponseBody::Empty,
         }
     }
 
@@ -134,120 +490,626 @@ impl<'a> Response<'a> {
         self
     }
 
-    pub fn body(mut self, body: &'a[u8]) -> Response {
-        self.body = Some(body);
+    pub fn headers(mut self, headers: &[(String, String)]) -> Response<'a> {
+        self.headers.extend_from_slice(headers);
         self
     }
 
-    pub fn bytes(self) -> Vec<u8> {
-        let mut header = format!("{} {}\n", self.version, self.status).to_string();
-        self.headers.iter().for_each(|item| { 
-            header += format!("{}: {}\n", item.0, item.1).as_str();
-        });
-        header += "\r\n";
-        let header = header.as_bytes();
-        if let Some(body) = self.body {
-            [header, body].concat()
-        } else {
-            header.to_owned()
+    pub fn body(mut self, body: impl Into<Cow<'a, [u8]>>) -> Response<'a> {
+        self.body = ResponseBody::Bytes(body.into());
+        self
+    }
+
+    /// Streams the body from `reader`, `len` bytes of it when given.
+    pub fn reader(mut self, reader: impl AsyncRead + Send + Unpin + 'a, len: Option<u64>) -> Response<'a> {
+        self.body = ResponseBody::Reader(Box::new(reader), len);
+        self
+    }
+
+    fn has_header(&self, key: &str) -> bool {
+        self.headers.iter().any(|(k, _)| k.eq_ignore_ascii_case(key))
+    }
+
+    /// The status line and headers, with the framing headers for the body filled in.
+    fn head(&mut self) -> Vec<u8> {
+        metrics::response_status(self.status);
+        // informational, 204 and 304 responses never have a body
+        let bodyless = self.status < 200 || self.status == 204 || self.status == 304;
+        if !bodyless && !self.has_header("Transfer-Encoding") && !self.has_header("Content-Length") {
+            let len = match &self.body {
+                ResponseBody::Empty => Some(0),
+                ResponseBody::Bytes(body) => Some(body.len() as u64),
+                ResponseBody::Reader(_, len) => *len,
+            };
+            match len {
+                Some(len) => self.headers.push(("Content-Length".into(), len.to_string())),
+                None => self.headers.push(("Transfer-Encoding".into(), "chunked".into())),
+            }
         }
+        if !self.has_header("Date") {
+            self.headers.push(("Date".into(), httpdate::fmt_http_date(std::time::SystemTime::now())));
+        }
+        if !self.has_header("Connection") && EXCHANGE.try_with(|e| e.get().closes()).unwrap_or(false) {
+            self.headers.push(("Connection".into(), "close".into()));
+        }
+        let mut head = format!("{} {} {}\r\n", self.version, self.status, reason(self.status));
+        for (key, value) in &self.headers {
+            head += &format!("{key}: {value}\r\n");
+        }
+        head += "\r\n";
+        head.into_bytes()
     }
-}
 
-pub async fn bind_and_listen<A>(addr: A, cmd_handle: CmdHandle<'static>, subscriber: EventSubscriber) -> Result<()>
+    /// Writes the response. A response without a body but with its own `Transfer-Encoding` header only
+    /// writes its head, the caller sends the chunks, as the event stream does. The body is left out
+    /// when answering a HEAD request, the framing headers still describe it.
+    pub async fn write_to<T>(mut self, stream: &mut T) -> Result<()>
     where
-        A: tokio::net::ToSocketAddrs
-{
-    let listener = TcpListener::bind(addr).await?;
+        T: AsyncWrite + Unpin,
+    {
+        let head = self.head();
+        if EXCHANGE.try_with(|e| e.get().head).unwrap_or(false) {
+            stream.write_all(&head).await?;
+            return Ok(());
+        }
+        match self.body {
+            ResponseBody::Empty => stream.write_all(&head).await?,
+            ResponseBody::Bytes(body) => stream.write_all(&[&head[..], &body].concat()).await?,
+            ResponseBody::Reader(reader, Some(len)) => {
+                stream.write_all(&head).await?;
+                let sent = tokio::io::copy(&mut reader.take(len), stream).await?;
+                // the client is waiting for the rest, the connection cannot be used any more
+                if sent != len {
+                    return Err(anyhow!("response body ended after {sent} of {len} bytes"));
+                }
+            },
+            ResponseBody::Reader(mut reader, None) => {
+                stream.write_all(&head).await?;
+                let mut buf = vec![0; CHUNK_SIZE];
+                loop {
+                    let n = reader.read(&mut buf).await?;
+                    if n == 0 {
+                        break;
+                    }
+                    stream.write_all(format!("{n:x}\r\n").as_bytes()).await?;
+                    stream.write_all(&buf[.. n]).await?;
+                    stream.write_all(b"\r\n").await?;
+                }
+                stream.write_all(b"0\r\n\r\n").await?;
+            },
+        }
+        Ok(())
+    }
+}
+
+/// A bound socket the server accepts connections on.
+enum Listener {
+    Tcp(TcpListener),
+    /// removed again when the server stops
+    #[cfg(unix)]
+    Unix(UnixListener, path::PathBuf),
+}
+
+/// Binds with socket2 so IPv6 sockets can be made IPv6 only or dual-stack regardless of the system
+/// default.
+fn bind_tcp(addr: SocketAddr, v6_only: bool) -> Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(v6_only)?;
+    }
+    // on windows this would let other programs take over the port
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    Ok(TcpListener::from_std(socket.into())?)
+}
+
+#[cfg(unix)]
+fn bind_unix(path: &path::Path) -> Result<Listener> {
+    use std::os::unix::fs::FileTypeExt;
+    // a socket left behind by an earlier run would make bind fail, one another process still listens
+    // on is left alone
+    if std::fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+        match std::os::unix::net::UnixStream::connect(path) {
+            Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => std::fs::remove_file(path)?,
+            Err(e) => return Err(anyhow!("socket {path:?} is not usable: {e}")),
+            Ok(_) => return Err(anyhow!("socket {path:?} is in use by another process")),
+        }
+    }
+    Ok(Listener::Unix(UnixListener::bind(path)?, path.to_owned()))
+}
+
+#[cfg(not(unix))]
+fn bind_unix(_path: &path::Path) -> Result<Listener> {
+    Err(anyhow!("unix domain sockets are not supported on this platform"))
+}
+
+async fn bind(endpoint: &Endpoint) -> Result<Vec<Listener>> {
+    match endpoint {
+        Endpoint::Tcp(addr) => Ok(vec![Listener::Tcp(bind_tcp(*addr, true)?)]),
+        Endpoint::DualStack(port) => Ok(vec![Listener::Tcp(bind_tcp((Ipv6Addr::UNSPECIFIED, *port).into(), false)?)]),
+        Endpoint::Host(host, port) => {
+            let mut addrs: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), *port)).await?.collect();
+            addrs.dedup();
+            addrs.into_iter().map(|addr| Ok(Listener::Tcp(bind_tcp(addr, true)?))).collect()
+        },
+        Endpoint::Unix(path) => Ok(vec![bind_unix(path)?]),
+    }
+}
+
+/// Listens on every configured endpoint, all of them serving the same routes. Endpoints that cannot
+/// be bound are skipped, it is an error if none can be. Returns once mpv shuts down and every
+/// connection has been closed.
+pub async fn bind_and_listen(config: Arc<Config>, cmd_handle: CmdHandle<'static>, subscriber: EventSubscriber) -> Result<()> {
+    let acceptor = if config.tls { Some(tls::acceptor(&config)?) } else { None };
+    let mut listeners = Vec::new();
+    for endpoint in config.endpoints() {
+        match bind(&endpoint).await {
+            Ok(bound) => {
+                info!("listening on {endpoint}");
+                listeners.extend(bound);
+            },
+            Err(e) => error!("unable to listen on {endpoint}: {e}"),
+        }
+    }
+    if listeners.is_empty() {
+        return Err(anyhow!("no address to listen on"));
+    }
+    let shutdown = mpv::shutdown(subscriber());
+    let advertisement = if config.mdns { advertise(&config, &listeners) } else { None };
+    let mut tasks = JoinSet::new();
+    for listener in listeners {
+        tasks.spawn(accept_loop(listener, config.clone(), acceptor.clone(), cmd_handle.clone(), subscriber.clone()));
+    }
+    if let Some(advertisement) = advertisement {
+        // withdrawn as soon as mpv shuts down, while the open connections close
+        shutdown.await;
+        let _ = tokio::task::spawn_blocking(move || advertisement.withdraw()).await;
+    }
+    while tasks.join_next().await.is_some() {}
+    Ok(())
+}
+
+/// Announces the remote over mdns on the bound tcp listeners, if any of them can be reached from the network.
+fn advertise(config: &Config, listeners: &[Listener]) -> Option<discovery::Advertisement> {
+    let addrs: Vec<SocketAddr> = listeners.iter().filter_map(|listener| match listener {
+        Listener::Tcp(listener) => listener.local_addr().ok(),
+        #[cfg(unix)]
+        Listener::Unix(..) => None,
+    }).collect();
+    match discovery::advertise(config, &addrs) {
+        Ok(advertisement) => Some(advertisement),
+        Err(e) => {
+            warning!("mdns announcement failed: {e}");
+            None
+        },
+    }
+}
+
+/// Accepts connections until mpv shuts down, then stops listening and waits for the open connections
+/// to close.
+async fn accept_loop(listener: Listener, config: Arc<Config>, acceptor: Option<TlsAcceptor>, cmd_handle: CmdHandle<'static>, subscriber: EventSubscriber) {
+    let mut connections = JoinSet::new();
+    let shutdown = mpv::shutdown(subscriber());
+    tokio::pin!(shutdown);
     loop {
-        let cmd_handle = cmd_handle.clone();
-        let (mut stream, _addr) = continue_on_err!(listener.accept().await);
-        let sub = subscriber.clone();
-        tokio::spawn(async move {
-            let request = Request::parse(&mut stream).await.unwrap();
-            let _ = handle_request(request, stream, cmd_handle, sub).await;
-        });
+        tokio::select! {
+            _shutdown = &mut shutdown => break,
+            // reap finished connections so the set does not grow
+            Some(_) = connections.join_next(), if !connections.is_empty() => continue,
+            accepted = accept(&listener) => {
+                let (stream, peer) = continue_on_err!(accepted);
+                let guard = limits::connect(&config, peer);
+                match stream {
+                    Accepted::Tcp(stream) => {
+                        connections.spawn(serve(stream, peer, guard, acceptor.clone(), config.clone(), cmd_handle.clone(), subscriber.clone()));
+                    },
+                    // unix sockets are local, they are served without tls
+                    #[cfg(unix)]
+                    Accepted::Unix(stream) => {
+                        connections.spawn(serve(stream, peer, guard, None, config.clone(), cmd_handle.clone(), subscriber.clone()));
+                    },
+                }
+            },
+        }
     }
+    #[cfg(unix)]
+    if let Listener::Unix(_, path) = &listener {
+        let _ = std::fs::remove_file(path);
+    }
+    drop(listener);
+    while connections.join_next().await.is_some() {}
+}
+
+/// A connection accepted by a `Listener`.
+enum Accepted {
+    Tcp(tokio::net::TcpStream),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixStream),
 }
 
-async fn handle_request<T>(request: Request, mut stream: T, mut cmd_handle: CmdHandle<'static>, subscriber: EventSubscriber) -> Result<()>
+async fn accept(listener: &Listener) -> std::io::Result<(Accepted, Option<IpAddr>)> {
+    match listener {
+        Listener::Tcp(listener) => {
+            let (stream, addr) = listener.accept().await?;
+            // the same client can connect over IPv4 and as an IPv4 mapped IPv6 address
+            Ok((Accepted::Tcp(stream), Some(addr.ip().to_canonical())))
+        },
+        #[cfg(unix)]
+        Listener::Unix(listener, _) => Ok((Accepted::Unix(listener.accept().await?.0), None)),
+    }
+}
+
+/// Serves a connection, or turns it away with a 429 when there was no connection slot for it.
+async fn serve<S>(stream: S, peer: Option<IpAddr>, guard: Option<limits::ConnectionGuard>, acceptor: Option<TlsAcceptor>, config: Arc<Config>, cmd_handle: CmdHandle<'static>, subscriber: EventSubscriber)
 where
-    T: AsyncRead + AsyncWrite + Send + Unpin + 'static + Debug,
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static + Debug,
 {
-    let url = parse_url(&request.path);
-    match &url[1 ..] {
-        [""] | ["", ""] => {
-            let response = Response::new("HTTP/1.1".into(), 200)
-                .header("Content-Type".into(), "text/html".into())
-                .body(INDEX_HTML);
-            stream.write_all(&response.bytes()).await?;
-            Ok(())
-            },
-        ["static", "main.js"] => {
-            let response = Response::new("HTTP/1.1".into(), 200)
-                .header("Content-Type".into(), "text/javascript".into())
-                .body(MAIN_JS);
-            stream.write_all(&response.bytes()).await?;
-            Ok(())
-            },
-        ["static", "output.css"] => {
-            let response = Response::new("HTTP/1.1".into(), 200)
-                .header("Content-Type".into(), "text/css".into())
-                .body(OUTPUT_CSS);
-            stream.write_all(&response.bytes()).await?;
-            Ok(())
+    if guard.is_none() {
+        warning!("connection limit reached, rejecting {peer:?}");
+    }
+    metrics::scope(async move {
+        let _ = match (acceptor, guard) {
+            (Some(acceptor), guard) => {
+                // a client that never finishes the handshake is dropped like an idle one
+                let handshake = tokio::time::timeout(Duration::from_secs(config.idle_timeout), acceptor.accept(stream));
+                let stream = match handshake.await {
+                    Ok(Ok(stream)) => stream,
+                    Ok(Err(e)) => {
+                        debug!("tls handshake failed: {e}");
+                        return;
+                    },
+                    Err(_) => {
+                        debug!("tls handshake with {peer:?} timed out");
+                        return;
+                    },
+                };
+                match guard {
+                    Some(_guard) => serve_connection(stream, peer, config, cmd_handle, subscriber).await,
+                    None => reject_connection(stream).await,
+                }
             },
-        ["static", "symbols", "material-symbols.woff2"] => {
-            let response = Response::new("HTTP/1.1".into(), 200)
-                .header("Content-Type".into(), "font/woff2".into())
-                .body(SYMBOLS_FONT);
-            stream.write_all(&response.bytes()).await?;
-            Ok(())
+            (None, Some(_guard)) => serve_connection(stream, peer, config, cmd_handle, subscriber).await,
+            (None, None) => reject_connection(stream).await,
+        };
+    }).await
+}
+
+/// Answers the first request of a connection over the connection limits with 429 and closes it.
+async fn reject_connection<T>(stream: T) -> Result<()>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut stream = BufReader::new(stream);
+    let _ = tokio::time::timeout(Duration::from_secs(5), Request::parse(&mut stream)).await;
+    let response = Response::new("HTTP/1.1", 429)
+        .header("Retry-After", "1")
+        .header("Connection", "close");
+    response.write_to(&mut stream).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+async fn serve_connection<T>(stream: T, peer: Option<IpAddr>, config: Arc<Config>, cmd_handle: CmdHandle<'static>, subscriber: EventSubscriber) -> Result<()>
+where
+    T: AsyncRead + AsyncWrite + Send + Unpin + 'static + Debug,
+{
+    let mut stream = BufReader::new(stream);
+    let idle_timeout = Duration::from_secs(config.idle_timeout);
+    // idle keep-alive connections are closed when mpv shuts down, requests in flight are finished
+    let shutdown = mpv::shutdown(subscriber());
+    tokio::pin!(shutdown);
+    loop {
+        let mut request = tokio::select! {
+            _shutdown = &mut shutdown => return Ok(()),
+            request = tokio::time::timeout(idle_timeout, Request::parse(&mut stream)) => match request {
+                Ok(request) => request?,
+                Err(_) => return Ok(()),
             },
+        };
+        request.peer = peer;
+        let path = request.route().to_string();
+        let exchange = Exchange::new(&request, config.max_body_size);
+        let answered = EXCHANGE.scope(Cell::new(exchange), async {
+            let stream = handle_request(request, stream, config.clone(), cmd_handle.clone(), subscriber.clone()).await?;
+            Ok::<_, anyhow::Error>(stream.map(|stream| (stream, EXCHANGE.with(Cell::get))))
+        }).await?;
+        let (mut reused, exchange) = match answered {
+            Some(answered) => answered,
+            None => return Ok(()),
+        };
+        if let Some(status) = metrics::take_status() {
+            metrics::request(metrics::route_label(&path, status), status);
+        }
+        reused.flush().await?;
+        if exchange.closes() {
+            return Ok(());
+        }
+        // routes are free to leave the body unread, what is left of it is skipped
+        if let Some(state) = exchange.body.filter(|body| !body.done) {
+            let mut body = Body { stream: &mut reused, state, limit: u64::MAX };
+            let drained = tokio::time::timeout(idle_timeout, body.drain()).await;
+            if !matches!(drained, Ok(Ok(()))) {
+                return Ok(());
+            }
+        }
+        stream = reused;
+    }
+}
+
+async fn serve_asset<T>(stream: &mut T, request: &Request, config: &Config, path: &str) -> Result<()>
+where
+    T: AsyncWrite + Unpin,
+{
+    let Some(asset) = assets::get(config, path).await else {
+        warning!("asset not found \"{path}\"");
+        Response::new("HTTP/1.1", 404).write_to(stream).await?;
+        return Ok(());
+    };
+    let last_modified = asset.modified.map(httpdate::fmt_http_date);
+    let not_modified = asset.not_modified(request);
+    let mut response = Response::new("HTTP/1.1", if not_modified { 304 } else { 200 })
+        .header("ETag", &asset.etag)
+        .header("Cache-Control", "no-cache")
+        .header("Vary", "Accept-Encoding");
+    if let Some(modified) = &last_modified {
+        response = response.header("Last-Modified", modified);
+    }
+    if not_modified {
+        response.write_to(stream).await?;
+        return Ok(());
+    }
+    response = response.header("Content-Type", asset.mime);
+    let encoding = request.header("Accept-Encoding").and_then(|accept| assets::Encoding::negotiate(accept));
+    let encoded = encoding.and_then(|enc| Some((enc, asset.encoded(enc)?)));
+    let response = match &encoded {
+        Some((enc, data)) => response
+            .header("Content-Encoding", enc.name())
+            .body(&data[..]),
+        None => response.body(&asset.data[..]),
+    };
+    response.write_to(stream).await?;
+    Ok(())
+}
+
+/// Answers a single request. Returns the stream if it is still usable for another request, it is kept
+/// when the connection is upgraded to a websocket.
+async fn handle_request<T>(mut request: Request, mut stream: T, config: Arc<Config>, mut cmd_handle: CmdHandle<'static>, subscriber: EventSubscriber) -> Result<Option<T>>
+where
+    T: AsyncBufRead + AsyncWrite + Send + Unpin + 'static + Debug,
+{
+    let route = request.route().to_string();
+    let url = parse_url(&route);
+    let json_route = matches!(&url[1 ..], ["api", ..] | ["file-picker", ..] | ["events"]);
+    if json_route {
+        request.response_headers = cors::headers(&config, &request);
+        // preflight requests never carry credentials
+        if request.method == Method::OPTIONS {
+            cors::preflight(&request, &mut stream).await?;
+            return Ok(Some(stream));
+        }
+    }
+    // the login page, the stylesheet it uses and what installing the app needs are served without credentials
+    let public = matches!(&url[1 ..], ["login"] | ["static", "output.css"] | ["static", "symbols", "material-symbols.woff2"]
+        // browsers fetch the manifest and its icons without credentials
+        | ["manifest.webmanifest"] | ["icons", _]);
+    let access = if public { auth::Access::Granted } else { auth::check(&config, &request) };
+    if access == auth::Access::Limited {
+        auth::too_many_attempts().headers(&request.response_headers).write_to(&mut stream).await?;
+        return Ok(Some(stream));
+    }
+    if access == auth::Access::Denied {
+        warning!("unauthenticated request for \"{}\"", request.path);
+        let login = assets::get(&config, "login.html").await.ok_or(anyhow!("login page missing"))?;
+        let response = Response::new("HTTP/1.1".into(), 401)
+            .headers(&request.response_headers)
+            .header("Content-Type".into(), login.mime)
+            .body(&login.data[..]);
+        response.write_to(&mut stream).await?;
+        return Ok(Some(stream));
+    }
+    let result = match &url[1 ..] {
+        ["login"] => match request.method {
+            Method::POST => auth::handle_login(&request, &mut stream, &config).await,
+            _ => serve_asset(&mut stream, &request, &config, "login.html").await,
+        },
+        [""] | ["", ""] => serve_asset(&mut stream, &request, &config, "index.html").await,
+        route @ (["manifest.webmanifest"] | ["icons", _] | ["sw.js"]) => pwa::handle(&request, route, &mut stream, &config).await,
+        ["static", rest @ ..] => {
+            let path = rest.iter().map(|c| percent_decode(c)).collect::<Vec<String>>().join("/");
+            serve_asset(&mut stream, &request, &config, &format!("static/{path}")).await
+        },
+        ["api", "upload"] => upload::handle(&request, &mut stream, &config, &mut cmd_handle).await,
+        ["api", "subtitles"] => upload::handle_subtitles(&request, &mut stream, &config, &mut cmd_handle).await,
+        ["api", "artwork", "current"] => artwork::handle(&request, &mut stream, &mut cmd_handle).await,
+        ["api", "screenshot"] => screenshot::handle(&request, &mut stream, &mut cmd_handle).await,
+        ["api", name] if name.starts_with("playlist.") => playlist::export(&request, name, &mut stream, &mut cmd_handle).await,
+        ["api", rest @ ..] => api::handle(&request, rest, &mut stream, &config, &mut cmd_handle).await,
+        ["media", rest @ ..] => stream::handle(&request, rest, &mut stream, &config, &mut cmd_handle).await,
         ["socket"] => {
-            tokio::spawn(async move {
-                let ws = websocket::WebSocketServer::handshake(request, stream).await?;
-                logger::debug!("new websocket connection: {ws:?}");
-                plugin::handle_client_connection(ws, &mut cmd_handle, subscriber()).await?;
-                Ok::<(), anyhow::Error>(())
-            });
+            // the websocket is served on this connection's task, so it keeps its connection slot
+            let peer = request.peer;
+            let ws = websocket::WebSocketServer::handshake(request, stream).await?;
+            logger::debug!("new websocket connection: {ws:?}");
+            metrics::request("/socket".into(), 101);
+            let _client = metrics::WsClient::connect();
+            plugin::handle_client_connection(ws, &mut cmd_handle, subscriber(), &config, peer).await?;
+            return Ok(None);
+        },
+        ["events"] if request.method == Method::GET => {
+            metrics::request("/events".into(), 200);
+            sse::handle(&request, stream, &mut cmd_handle, subscriber()).await?;
+            return Ok(None);
+        },
+        ["events"] => {
+            let response = Response::new("HTTP/1.1", 405)
+                .headers(&request.response_headers)
+                .header("Allow", "GET");
+            response.write_to(&mut stream).await?;
+            Ok(())
+        },
+        ["metrics"] => {
+            let payload = metrics::render(&mut cmd_handle);
+            let response = Response::new("HTTP/1.1", 200)
+                .header("Content-Type", "text/plain; version=0.0.4; charset=utf-8")
+                .body(payload.as_bytes());
+            response.write_to(&mut stream).await?;
             Ok(())
         },
         ["file-picker", rest @ ..] => {
-            let mut fpath = std::env::current_dir()?;
-            for f in rest {
-                fpath.push(f);
-            }
-            let mut entries = fs::read_dir(fpath).await?;
-            let mut dirs :Vec<String> = Vec::new();
-            let mut files :Vec<String> = Vec::new();
-            while let Some(entry) = entries.next_entry().await? {
-                let name = entry.file_name().into_string().map_err(|e| {anyhow!("unable to format OsString \"{e:?}\"")})?;
-                if entry.path().is_dir() {
-                    dirs.push(name);
-                } else if entry.path().is_file() {
-                    files.push(name);
-                } else {
-                    panic!("not a file or dir");
-                }
-            }
-            let payload = json!({
-                "dirs": dirs,
-                "files": files
-            }).to_string();
+            let path = rest.iter().map(|c| percent_decode(c)).collect::<Vec<String>>().join("/");
+            let options = media::ListOptions::from_query(&request.query());
+            let (status, payload) = match media::list(&config, &path, &options).await {
+                Ok(listing) => (200, serde_json::to_string(&listing)?),
+                Err(e) => {
+                    warning!("file picker \"{path}\": {e}");
+                    let status = e.downcast_ref::<media::AccessError>().map_or(500, |e| e.status());
+                    (status, json!({ "error": e.to_string() }).to_string())
+                },
+            };
             debug!("file picker {payload}");
-            let response = Response::new("HTTP/1.1".into(), 200)
+            let response = Response::new("HTTP/1.1".into(), status)
+                .headers(&request.response_headers)
                 .header("Content-Type".into(), "application/json".into())
                 .body(payload.as_bytes());
-            stream.write_all(&response.bytes()).await?;
+            response.write_to(&mut stream).await?;
             Ok(())
         },
         path => {
             warning!("bad request path not found \"{path:?}\"");
             let response = Response::new("HTTP/1.1".into(), 404)
                 .header("Content-Type".into(), "text/html".into());
-            stream.write_all(&response.bytes()).await?;
+            response.write_to(&mut stream).await?;
             Ok(())
             },
+    };
+    result?;
+    Ok(Some(stream))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    async fn parse_with_body(raw: &str, limit: u64) -> Result<Vec<u8>> {
+        let mut stream = BufReader::new(std::io::Cursor::new(raw.as_bytes().to_vec()));
+        let request = Request::parse(&mut stream).await?;
+        let mut body = request.body(&mut stream, limit)?;
+        body.read_to_end().await
+    }
+
+    async fn written(response: Response<'_>) -> String {
+        let mut out = Vec::new();
+        response.write_to(&mut out).await.unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[tokio::test]
+    async fn response_framing() {
+        let response = written(Response::new("HTTP/1.1", 200).body(b"hello")).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("\r\nContent-Length: 5\r\n"));
+        assert!(response.contains("\r\nDate: "));
+        assert!(response.ends_with("\r\n\r\nhello"));
+        let response = written(Response::new("HTTP/1.1", 101)).await;
+        assert!(!response.contains("Content-Length"));
+        let response = written(Response::new("HTTP/1.1", 200).reader(&b"hello world"[..], Some(5))).await;
+        assert!(response.contains("\r\nContent-Length: 5\r\n"));
+        assert!(response.ends_with("\r\n\r\nhello"));
+        let response = written(Response::new("HTTP/1.1", 200).reader(&b"hello"[..], None)).await;
+        assert!(response.contains("\r\nTransfer-Encoding: chunked\r\n"));
+        assert!(response.ends_with("\r\n\r\n5\r\nhello\r\n0\r\n\r\n"));
+    }
+
+    #[tokio::test]
+    async fn body_content_length() {
+        let raw = "POST /api/seek HTTP/1.1\r\nContent-Length: 11\r\n\r\nrelative=10extra";
+        let body = parse_with_body(raw, 1024).await.unwrap();
+        assert_eq!(body, b"relative=10");
+    }
+
+    #[tokio::test]
+    async fn body_chunked() {
+        let raw = "POST /api/seek HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nrelat\r\n6;ext=1\r\nive=10\r\n0\r\nTrailer: x\r\n\r\n";
+        let body = parse_with_body(raw, 1024).await.unwrap();
+        assert_eq!(body, b"relative=10");
+    }
+
+    #[tokio::test]
+    async fn body_too_large() {
+        let raw = "POST /api/seek HTTP/1.1\r\nContent-Length: 11\r\n\r\nrelative=10";
+        assert!(parse_with_body(raw, 4).await.unwrap_err().is::<PayloadTooLarge>());
+        let raw = "POST /api/seek HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nb\r\nrelative=10\r\n0\r\n\r\n";
+        assert!(parse_with_body(raw, 4).await.unwrap_err().is::<PayloadTooLarge>());
+    }
+
+    /// answers a request after reading up to `read` bytes of its body, as a route would
+    async fn answer(raw: &str, read: usize) -> (Exchange, String, BufReader<std::io::Cursor<Vec<u8>>>) {
+        let mut stream = BufReader::new(std::io::Cursor::new(raw.as_bytes().to_vec()));
+        let request = Request::parse(&mut stream).await.unwrap();
+        EXCHANGE.scope(Cell::new(Exchange::new(&request, 16)), async {
+            let mut buf = vec![0; read];
+            let mut filled = 0;
+            while filled < read {
+                match request.body(&mut stream, 1024).unwrap().read(&mut buf[filled ..]).await.unwrap() {
+                    0 => break,
+                    n => filled += n,
+                }
+            }
+            let response = written(Response::new("HTTP/1.1", 204)).await;
+            (EXCHANGE.with(Cell::get), response, stream)
+        }).await
+    }
+
+    #[tokio::test]
+    async fn unread_body() {
+        // a short leftover is skipped and the next request read
+        let (exchange, response, mut stream) = answer("POST /a HTTP/1.1\r\nContent-Length: 5\r\n\r\nhelloGET /b HTTP/1.1\r\n\r\n", 2).await;
+        assert!(!exchange.closes());
+        assert!(!response.contains("Connection: close"));
+        let mut body = Body { stream: &mut stream, state: exchange.body.unwrap(), limit: u64::MAX };
+        body.drain().await.unwrap();
+        assert_eq!(Request::parse(&mut stream).await.unwrap().path, "/b");
+
+        // too much left over, or a chunked body left part way, closes the connection
+        let (exchange, response, _) = answer("POST /a HTTP/1.1\r\nContent-Length: 100\r\n\r\nhello", 0).await;
+        assert!(exchange.closes());
+        assert!(response.contains("\r\nConnection: close\r\n"));
+        let (exchange, _, _) = answer("POST /a HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n", 2).await;
+        assert!(exchange.closes());
+        // unless it was read to the end
+        let (exchange, _, _) = answer("POST /a HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n", 64).await;
+        assert!(!exchange.closes());
+        let (exchange, response, _) = answer("GET /a HTTP/1.1\r\nConnection: close\r\n\r\n", 0).await;
+        assert!(exchange.closes());
+        assert!(response.contains("\r\nConnection: close\r\n"));
+    }
+
+    #[tokio::test]
+    async fn head_without_body() {
+        let request = Request::parse(&mut BufReader::new(&b"HEAD /static/app.js HTTP/1.1\r\n\r\n"[..])).await.unwrap();
+        let response = EXCHANGE.scope(Cell::new(Exchange::new(&request, 0)), async {
+            written(Response::new("HTTP/1.1", 200).header("ETag", "\"abc\"").body(b"hello")).await
+        }).await;
+        assert!(response.contains("\r\nContent-Length: 5\r\n"));
+        assert!(response.contains("\r\nETag: \"abc\"\r\n"));
+        assert!(response.ends_with("\r\n\r\n"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn stale_unix_socket() {
+        let dir = TempDir::new("unix-socket");
+        let path = dir.join("remote.sock");
+        // a socket someone listens on is not taken over
+        let listening = bind_unix(&path).unwrap();
+        assert!(bind_unix(&path).is_err());
+        // a leftover one is replaced
+        drop(listening);
+        assert!(path.exists());
+        assert!(bind_unix(&path).is_ok());
     }
 }
@@ -0,0 +1,172 @@
+use std::time::Duration;
+use serde_json::{json, Value};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::broadcast::{self, error::RecvError};
+use anyhow::Result;
+use crate::logger::warning;
+use crate::metrics;
+use crate::mpv::{CmdHandle, Event};
+use crate::plugin;
+use crate::server::{Request, Response};
+
+/// comment line sent when nothing else was, so proxies and clients keep the stream open
+const HEARTBEAT: Duration = Duration::from_secs(15);
+/// how long a client waits before reconnecting after the stream drops, in milliseconds
+const RETRY_MS: u64 = 3000;
+
+/// writes `data` as one chunk of a chunked response body
+async fn write_chunk<T>(stream: &mut T, data: &str) -> Result<()>
+where
+    T: AsyncWrite + Unpin,
+{
+    stream.write_all(format!("{:x}\r\n{data}\r\n", data.len()).as_bytes()).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+/// formats a message as an event with the given id, `data` holds the same JSON the websocket sends
+fn event(id: u64, payload: &Value) -> String {
+    format!("id: {id}\ndata: {payload}\n\n")
+}
+
+/// What the stream reports, mpv's state when serving clients.
+trait Source {
+    fn status_payload(&mut self) -> Value;
+    fn time_pos_payload(&mut self) -> Option<Value>;
+    fn event_payload(&mut self, event: &Event) -> Option<Value>;
+}
+
+impl Source for CmdHandle<'_> {
+    fn status_payload(&mut self) -> Value {
+        json!({ "event": "status", "data": self.status() })
+    }
+
+    fn time_pos_payload(&mut self) -> Option<Value> {
+        plugin::time_pos_payload(self)
+    }
+
+    fn event_payload(&mut self, event: &Event) -> Option<Value> {
+        plugin::event_payload(event, self)
+    }
+}
+
+/// Serves `GET /events`: streams the websocket's messages as `text/event-stream`. Every stream starts
+/// with a `status` event holding the whole player state. Events are not replayed: a client
+/// reconnecting with `Last-Event-ID` resyncs from that status, and event ids carry on from the one it
+/// last saw.
+pub async fn handle<T>(request: &Request, stream: T, cmd_handle: &mut CmdHandle<'_>, event_chan: broadcast::Receiver<Event>) -> Result<()>
+where
+    T: AsyncWrite + Unpin,
+{
+    serve(request, stream, cmd_handle, event_chan).await
+}
+
+async fn serve<T, S>(request: &Request, mut stream: T, source: &mut S, mut event_chan: broadcast::Receiver<Event>) -> Result<()>
+where
+    T: AsyncWrite + Unpin,
+    S: Source,
+{
+    let mut id = request.header("Last-Event-ID")
+        .and_then(|id| id.trim().parse::<u64>().ok())
+        .map_or(0, |id| id + 1);
+    let response = Response::new("HTTP/1.1", 200)
+        .headers(&request.response_headers)
+        .header("Content-Type", "text/event-stream")
+        .header("Cache-Control", "no-cache")
+        .header("Transfer-Encoding", "chunked")
+        .header("Connection", "close");
+    response.write_to(&mut stream).await?;
+    write_chunk(&mut stream, &format!("retry: {RETRY_MS}\n{}", event(id, &source.status_payload()))).await?;
+    let mut heartbeat = tokio::time::interval_at(tokio::time::Instant::now() + HEARTBEAT, HEARTBEAT);
+    loop {
+        let payload = tokio::select! {
+            _time_sync_sleep = tokio::time::sleep(plugin::time_sync_interval()) => {
+                source.time_pos_payload()
+            },
+            _heartbeat = heartbeat.tick() => {
+                write_chunk(&mut stream, ": heartbeat\n\n").await?;
+                continue;
+            },
+            mpv_msg = event_chan.recv() => match mpv_msg {
+                Ok(Event::Shutdown) => break,
+                Ok(mpv_msg) => source.event_payload(&mpv_msg),
+                // some events were missed, resend everything they could have changed
+                Err(RecvError::Lagged(n)) => {
+                    warning!("event stream lagged behind by {n} events");
+                    metrics::lagged(n);
+                    Some(source.status_payload())
+                },
+                Err(RecvError::Closed) => break,
+            },
+        };
+        let Some(payload) = payload else { continue };
+        id += 1;
+        write_chunk(&mut stream, &event(id, &payload)).await?;
+        heartbeat.reset();
+    }
+    // last chunk
+    stream.write_all(b"0\r\n\r\n").await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, BufReader};
+
+    struct Player;
+
+    impl Source for Player {
+        fn status_payload(&mut self) -> Value {
+            json!({ "event": "status", "data": { "pause": false } })
+        }
+
+        fn time_pos_payload(&mut self) -> Option<Value> {
+            None
+        }
+
+        fn event_payload(&mut self, event: &Event) -> Option<Value> {
+            matches!(event, Event::EndFile).then(|| json!({ "event": "pause", "data": true }))
+        }
+    }
+
+    /// reads from `stream` into `out` until it holds `text`
+    async fn read_until<T: tokio::io::AsyncRead + Unpin>(stream: &mut T, out: &mut String, text: &str) {
+        let mut buf = [0; 1024];
+        while !out.contains(text) {
+            let n = stream.read(&mut buf).await.unwrap();
+            assert!(n > 0, "stream ended before {text:?} in {out:?}");
+            out.push_str(std::str::from_utf8(&buf[.. n]).unwrap());
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn framing() {
+        let raw = "GET /events HTTP/1.1\r\nLast-Event-ID: 41\r\n\r\n";
+        let request = Request::parse(&mut BufReader::new(raw.as_bytes())).await.unwrap();
+        let (events, receiver) = broadcast::channel(4);
+        let (server, mut client) = tokio::io::duplex(4096);
+        let task = tokio::spawn(async move { serve(&request, server, &mut Player, receiver).await });
+
+        let mut out = String::new();
+        // the status comes first, numbered after the last id the client saw
+        let status = "id: 42\ndata: {\"data\":{\"pause\":false},\"event\":\"status\"}\n\n";
+        let first = format!("retry: {RETRY_MS}\n{status}");
+        read_until(&mut client, &mut out, &first).await;
+        assert!(out.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(out.contains("\r\nContent-Type: text/event-stream\r\n"));
+        assert!(out.contains(&format!("\r\n\r\n{:x}\r\n{first}\r\n", first.len())));
+
+        events.send(Event::EndFile).unwrap();
+        let pause = "id: 43\ndata: {\"data\":true,\"event\":\"pause\"}\n\n";
+        read_until(&mut client, &mut out, &format!("{:x}\r\n{pause}\r\n", pause.len())).await;
+
+        // quiet streams get a comment line every HEARTBEAT
+        read_until(&mut client, &mut out, "d\r\n: heartbeat\n\n\r\n").await;
+
+        events.send(Event::Shutdown).unwrap();
+        client.read_to_string(&mut out).await.unwrap();
+        assert!(out.ends_with(": heartbeat\n\n\r\n0\r\n\r\n"));
+        task.await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,282 @@
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite};
+use anyhow::Result;
+use crate::config::Config;
+use crate::logger::warning;
+use crate::media::{self, AccessError, Kind};
+use crate::mpv::CmdHandle;
+use crate::server::{Method, Request, Response, json_error};
+
+/// ranges answered in one response, requests for more get the whole file
+const MAX_RANGES: usize = 16;
+
+/// An inclusive byte range of a file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl ByteRange {
+    fn len(&self) -> u64 {
+        self.end - self.start + 1
+    }
+}
+
+/// What a `Range` header asks of a file of `len` bytes.
+#[derive(Debug, PartialEq)]
+pub enum Ranges {
+    /// no usable Range header, the whole file is sent
+    Full,
+    Partial(Vec<ByteRange>),
+    /// none of the ranges overlap the file
+    Unsatisfiable,
+}
+
+/// Parses a `Range` header such as `bytes=0-499, 1000-, -500`. Headers that cannot be parsed, are not in
+/// bytes or ask for too many ranges are ignored, as HTTP allows.
+pub fn parse_ranges(header: &str, len: u64) -> Ranges {
+    let Some(specs) = header.trim().strip_prefix("bytes=") else { return Ranges::Full };
+    let mut ranges = Vec::new();
+    for spec in specs.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        let Some((start, end)) = spec.split_once('-') else { return Ranges::Full };
+        let range = match (start.trim(), end.trim()) {
+            // the last `end` bytes
+            ("", end) => match end.parse::<u64>() {
+                Ok(0) => continue,
+                Ok(suffix) if len > 0 => ByteRange { start: len.saturating_sub(suffix), end: len - 1 },
+                Ok(_) => continue,
+                Err(_) => return Ranges::Full,
+            },
+            (start, end) => {
+                let Ok(start) = start.parse::<u64>() else { return Ranges::Full };
+                let end = match end {
+                    "" => u64::MAX,
+                    end => match end.parse::<u64>() {
+                        Ok(end) if end >= start => end,
+                        _ => return Ranges::Full,
+                    },
+                };
+                if start >= len {
+                    continue;
+                }
+                ByteRange { start, end: end.min(len - 1) }
+            },
+        };
+        ranges.push(range);
+    }
+    match ranges.len() {
+        0 => Ranges::Unsatisfiable,
+        n if n > MAX_RANGES => Ranges::Full,
+        _ => Ranges::Partial(ranges),
+    }
+}
+
+/// Validator of a file's contents, changes whenever its size or modification time does.
+fn etag(len: u64, modified: Option<SystemTime>) -> String {
+    let mtime = modified
+        .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_nanos());
+    format!("\"{len:x}-{mtime:x}\"")
+}
+
+/// Whether an `If-Range` condition still holds, so the requested ranges may be sent. Entity tags must
+/// match strongly, dates exactly.
+fn if_range(request: &Request, etag: &str, modified: Option<SystemTime>) -> bool {
+    let Some(condition) = request.header("If-Range").map(|c| c.trim()) else { return true };
+    if condition.starts_with('"') || condition.starts_with("W/") {
+        return condition == etag;
+    }
+    match (httpdate::parse_http_date(condition), modified) {
+        (Ok(date), Some(modified)) => httpdate::fmt_http_date(modified) == httpdate::fmt_http_date(date),
+        _ => false,
+    }
+}
+
+/// the local file mpv is playing, None for streams and when nothing is loaded
+fn current_file(cmd_handle: &mut CmdHandle<'_>) -> Option<PathBuf> {
+    let path = cmd_handle.get_property::<String>("path").ok()?;
+    if path.contains("://") {
+        return None;
+    }
+    let path = PathBuf::from(path);
+    if path.is_relative() {
+        return Some(Path::new(&cmd_handle.get_property::<String>("working-directory").ok()?).join(path));
+    }
+    Some(path)
+}
+
+/// opens `path` positioned at `start`
+async fn open_at(path: &Path, start: u64) -> Result<File> {
+    let mut file = File::open(path).await?;
+    file.seek(SeekFrom::Start(start)).await?;
+    Ok(file)
+}
+
+/// Serves `/media/current`, the file mpv is playing, and `/media/files/<root>/<path>`, a file from the
+/// file picker. Both honour `Range` and `If-Range`, so browsers can seek in them, and refuse anything
+/// outside of the media roots.
+pub async fn handle<T>(request: &Request, url: &[&str], stream: &mut T, config: &Config, cmd_handle: &mut CmdHandle<'_>) -> Result<()>
+where
+    T: AsyncWrite + Unpin,
+{
+    let path = match url {
+        ["current"] => current_file(cmd_handle)
+            .ok_or((404, "nothing local is playing".to_string()))
+            .and_then(|path| media::within_roots(config, &path).map_err(|e| (e.status(), e.to_string()))),
+        ["files", rest @ ..] => {
+            let path = rest.iter().map(|c| crate::server::percent_decode(c)).collect::<Vec<String>>().join("/");
+            media::resolve(config, &path).map_err(|e| (e.status(), e.to_string()))
+        },
+        _ => Err((404, AccessError::NotFound.to_string())),
+    };
+    let path = match request.method {
+        Method::GET | Method::HEAD => path,
+        _ => Err((405, format!("expected GET got {:?}", request.method))),
+    };
+    let result = match path {
+        Ok(path) => send_file(request, stream, &path).await
+            .map_err(|e| (e.downcast_ref::<AccessError>().map_or(500, |e| e.status()), e.to_string())),
+        Err(e) => Err(e),
+    };
+    if let Err((status, error)) = result {
+        warning!("media \"{}\": {error}", request.path);
+        json_error(request, stream, status, &error).await?;
+    }
+    Ok(())
+}
+
+async fn send_file<T>(request: &Request, stream: &mut T, path: &Path) -> Result<()>
+where
+    T: AsyncWrite + Unpin,
+{
+    let metadata = tokio::fs::metadata(path).await?;
+    if !metadata.is_file() {
+        return Err(AccessError::NotFound.into());
+    }
+    let len = metadata.len();
+    let modified = metadata.modified().ok();
+    let etag = etag(len, modified);
+    let last_modified = modified.map(httpdate::fmt_http_date);
+    let mime = Kind::mime_type(path);
+    let head = |status| {
+        let mut response = Response::new("HTTP/1.1", status)
+            .headers(&request.response_headers)
+            .header("Accept-Ranges", "bytes")
+            .header("ETag", &etag)
+            .header("Cache-Control", "no-cache");
+        if let Some(modified) = &last_modified {
+            response = response.header("Last-Modified", modified);
+        }
+        response
+    };
+    let ranges = match request.header("Range") {
+        Some(range) if if_range(request, &etag, modified) => parse_ranges(range, len),
+        _ => Ranges::Full,
+    };
+    let (response, body, body_len): (_, Box<dyn AsyncRead + Send + Unpin>, u64) = match ranges {
+        Ranges::Full => (head(200).header("Content-Type", mime), Box::new(File::open(path).await?), len),
+        Ranges::Unsatisfiable => {
+            head(416).header("Content-Range", &format!("bytes */{len}")).write_to(stream).await?;
+            return Ok(());
+        },
+        Ranges::Partial(ranges) if ranges.len() == 1 => {
+            let range = ranges[0];
+            let response = head(206)
+                .header("Content-Type", mime)
+                .header("Content-Range", &format!("bytes {}-{}/{len}", range.start, range.end));
+            (response, Box::new(open_at(path, range.start).await?.take(range.len())), range.len())
+        },
+        // multipart/byteranges, every range is a part with its own Content-Range
+        Ranges::Partial(ranges) => {
+            let boundary = format!("{:016x}", rand::random::<u64>());
+            let mut body: Box<dyn AsyncRead + Send + Unpin> = Box::new(tokio::io::empty());
+            let mut body_len = 0;
+            for range in &ranges {
+                let part = format!("\r\n--{boundary}\r\nContent-Type: {mime}\r\nContent-Range: bytes {}-{}/{len}\r\n\r\n", range.start, range.end);
+                body_len += part.len() as u64 + range.len();
+                let file = open_at(path, range.start).await?;
+                body = Box::new(body.chain(std::io::Cursor::new(part)).chain(file.take(range.len())));
+            }
+            let end = format!("\r\n--{boundary}--\r\n");
+            body_len += end.len() as u64;
+            let response = head(206).header("Content-Type", &format!("multipart/byteranges; boundary={boundary}"));
+            (response, Box::new(body.chain(std::io::Cursor::new(end))), body_len)
+        },
+    };
+    response.reader(body, Some(body_len)).write_to(stream).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+    use tokio::io::BufReader;
+
+    fn range(start: u64, end: u64) -> ByteRange {
+        ByteRange { start, end }
+    }
+
+    #[test]
+    fn ranges() {
+        assert_eq!(parse_ranges("bytes=0-499", 1000), Ranges::Partial(vec![range(0, 499)]));
+        assert_eq!(parse_ranges("bytes=500-", 1000), Ranges::Partial(vec![range(500, 999)]));
+        assert_eq!(parse_ranges("bytes=-200", 1000), Ranges::Partial(vec![range(800, 999)]));
+        assert_eq!(parse_ranges("bytes=900-2000", 1000), Ranges::Partial(vec![range(900, 999)]));
+        assert_eq!(parse_ranges("bytes=0-0, -1", 1000), Ranges::Partial(vec![range(0, 0), range(999, 999)]));
+        assert_eq!(parse_ranges("bytes=1000-", 1000), Ranges::Unsatisfiable);
+        assert_eq!(parse_ranges("bytes=5-1", 1000), Ranges::Full);
+        assert_eq!(parse_ranges("items=0-1", 1000), Ranges::Full);
+        assert_eq!(parse_ranges(&format!("bytes={}", vec!["0-1"; 17].join(",")), 1000), Ranges::Full);
+    }
+
+    /// the head and body of `send_file`'s answer to a request for `path`
+    async fn fetch(path: &Path, headers: &str) -> (String, Vec<u8>) {
+        let raw = format!("GET /media/file HTTP/1.1\r\n{headers}\r\n");
+        let request = Request::parse(&mut BufReader::new(raw.as_bytes())).await.unwrap();
+        let mut out = Vec::new();
+        send_file(&request, &mut out, path).await.unwrap();
+        let end = out.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+        let body = out.split_off(end);
+        (String::from_utf8(out).unwrap(), body)
+    }
+
+    fn header<'a>(head: &'a str, name: &str) -> Option<&'a str> {
+        head.lines().find_map(|line| line.strip_prefix(name)?.strip_prefix(": "))
+    }
+
+    #[tokio::test]
+    async fn byteranges() {
+        let dir = TempDir::new("byteranges");
+        let path = dir.join("clip.mp4");
+        std::fs::write(&path, b"0123456789").unwrap();
+        let (head, body) = fetch(&path, "Range: bytes=0-1,-3\r\n").await;
+        assert!(head.starts_with("HTTP/1.1 206"));
+        let boundary = header(&head, "Content-Type").unwrap().strip_prefix("multipart/byteranges; boundary=").unwrap();
+        assert_eq!(header(&head, "Content-Length"), Some(body.len().to_string().as_str()));
+        let expected = format!("\r\n--{boundary}\r\nContent-Type: video/mp4\r\nContent-Range: bytes 0-1/10\r\n\r\n01\
+            \r\n--{boundary}\r\nContent-Type: video/mp4\r\nContent-Range: bytes 7-9/10\r\n\r\n789\
+            \r\n--{boundary}--\r\n");
+        assert_eq!(String::from_utf8(body).unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn stale_if_range() {
+        let dir = TempDir::new("if-range");
+        let path = dir.join("clip.mp4");
+        std::fs::write(&path, b"0123456789").unwrap();
+        let (head, body) = fetch(&path, "Range: bytes=2-3\r\nIf-Range: \"stale\"\r\n").await;
+        assert!(head.starts_with("HTTP/1.1 200"));
+        assert_eq!(header(&head, "Content-Range"), None);
+        assert_eq!(body, b"0123456789");
+
+        // with the current validator the range is served
+        let etag = header(&head, "ETag").unwrap().to_string();
+        let (head, body) = fetch(&path, &format!("Range: bytes=2-3\r\nIf-Range: {etag}\r\n")).await;
+        assert!(head.starts_with("HTTP/1.1 206"));
+        assert_eq!(body, b"23");
+    }
+}
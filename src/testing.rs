@@ -0,0 +1,29 @@
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+/// A directory of its own for a test, removed with everything in it when dropped, so a failing
+/// assert does not leave it behind.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    /// `name` tells the tests apart, the process id concurrent runs of the test binary
+    pub fn new(name: &str) -> TempDir {
+        let path = std::env::temp_dir().join(format!("mpv-remote-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
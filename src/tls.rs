@@ -0,0 +1,109 @@
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Arc;
+use anyhow::{Result, anyhow};
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::{self, pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject}};
+use crate::config::Config;
+use crate::logger::info;
+
+const GENERATED_CERT: &str = "mpv-remote-cert.pem";
+const GENERATED_KEY: &str = "mpv-remote-key.pem";
+
+/// Builds the TLS acceptor from the configured certificate and key. Without configured files a
+/// self-signed certificate is generated into the script-opts directory on first run and reused after.
+pub fn acceptor(config: &Config) -> Result<TlsAcceptor> {
+    let (cert_path, key_path) = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => (cert.clone(), key.clone()),
+        (None, None) => {
+            let dir = Config::dir()?;
+            let (cert, key) = (dir.join(GENERATED_CERT), dir.join(GENERATED_KEY));
+            if !cert.exists() || !key.exists() {
+                generate_self_signed(config, &cert, &key)?;
+            }
+            (cert, key)
+        },
+        _ => return Err(anyhow!("tls_cert and tls_key must be set together")),
+    };
+    let certs = CertificateDer::pem_file_iter(&cert_path)
+        .map_err(|e| anyhow!("reading certificate {cert_path:?}: {e}"))?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| anyhow!("reading certificate {cert_path:?}: {e}"))?;
+    let key = PrivateKeyDer::from_pem_file(&key_path)
+        .map_err(|e| anyhow!("reading private key {key_path:?}: {e}"))?;
+    let server_config = rustls::ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+fn generate_self_signed(config: &Config, cert_path: &Path, key_path: &Path) -> Result<()> {
+    info!("generating self-signed certificate {cert_path:?}");
+    let mut names = vec!["localhost".to_string()];
+    if config.host != "0.0.0.0" && config.host != "::" {
+        names.push(config.host.clone());
+    }
+    let generated = rcgen::generate_simple_self_signed(names)?;
+    if let Some(dir) = cert_path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(cert_path, generated.cert.pem())?;
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(key_path)?.write_all(generated.key_pair.serialize_pem().as_bytes())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::TlsConnector;
+    use tokio_rustls::rustls::pki_types::ServerName;
+
+    #[tokio::test]
+    async fn self_signed_handshake() {
+        let dir = TempDir::new("tls");
+        let (cert, key) = (dir.join(GENERATED_CERT), dir.join(GENERATED_KEY));
+        generate_self_signed(&Config::default(), &cert, &key).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(&key).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+        let config = Config { tls_cert: Some(cert.clone()), tls_key: Some(key.clone()), ..Config::default() };
+        let acceptor = acceptor(&config).unwrap();
+        assert!(super::acceptor(&Config { tls_key: None, ..config }).is_err());
+
+        // a client trusting the generated certificate completes a handshake for localhost
+        let mut roots = rustls::RootCertStore::empty();
+        for cert in CertificateDer::pem_file_iter(&cert).unwrap() {
+            roots.add(cert.unwrap()).unwrap();
+        }
+        let client_config = rustls::ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let (client, server) = tokio::io::duplex(16 * 1024);
+        let server = tokio::spawn(async move {
+            let mut stream = acceptor.accept(server).await.unwrap();
+            stream.write_all(b"hello").await.unwrap();
+            stream.shutdown().await.unwrap();
+        });
+        let name = ServerName::try_from("localhost").unwrap();
+        let mut stream = TlsConnector::from(Arc::new(client_config)).connect(name, client).await.unwrap();
+        let mut greeting = String::new();
+        stream.read_to_string(&mut greeting).await.unwrap();
+        assert_eq!(greeting, "hello");
+        server.await.unwrap();
+    }
+}
@@ -0,0 +1,391 @@
+use std::path::{Path, PathBuf};
+use serde_json::json;
+use tokio::fs;
+use tokio::io::{AsyncBufRead, AsyncWrite, AsyncWriteExt};
+use anyhow::{Result, anyhow, bail};
+use crate::config::Config;
+use crate::{media, plugin};
+use crate::logger::{info, warning};
+use crate::mpv::CmdHandle;
+use crate::server::{Body, PayloadTooLarge, Request, Response, json_error};
+
+/// longest multipart header block accepted
+const MAX_PART_HEADER: usize = 8 * 1024;
+
+/// Turns a client supplied file name into a single path component: directories are dropped, as are
+/// control characters and leading dots, and the length is capped.
+pub fn safe_name(name: &str) -> String {
+    let base = name.rsplit(['/', '\\']).next().unwrap_or("");
+    let mut cleaned: String = base.chars()
+        .filter(|c| !c.is_control() && !matches!(c, ':' | '*' | '?' | '"' | '<' | '>' | '|'))
+        .collect::<String>()
+        .trim()
+        .trim_start_matches('.')
+        .to_string();
+    while cleaned.len() > 200 {
+        cleaned.pop();
+    }
+    if cleaned.is_empty() { "upload".into() } else { cleaned }
+}
+
+/// `name` followed by the names tried when it is taken, with " (n)" appended before the extension
+fn candidate_names(name: &str) -> impl Iterator<Item = String> + '_ {
+    let (stem, ext) = match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => (stem, format!(".{ext}")),
+        _ => (name, String::new()),
+    };
+    std::iter::once(name.to_string()).chain((1 ..).map(move |n| format!("{stem} ({n}){ext}")))
+}
+
+/// Writes incoming data to a `.part` file that is renamed into place once complete, and removed
+/// if the upload fails.
+struct Upload {
+    file: fs::File,
+    part: PathBuf,
+    path: PathBuf,
+}
+
+impl Upload {
+    /// Creates the `.part` file of the first name in `dir` that is not taken. The file is created
+    /// exclusively, so concurrent uploads of the same name end up with different names.
+    async fn create(dir: &Path, name: &str) -> Result<Self> {
+        for name in candidate_names(&safe_name(name)) {
+            let path = dir.join(&name);
+            if fs::try_exists(&path).await? {
+                continue;
+            }
+            let part = dir.join(format!("{name}.part"));
+            match fs::OpenOptions::new().write(true).create_new(true).open(&part).await {
+                Ok(file) => return Ok(Upload { file, part, path }),
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        unreachable!("the candidate names never run out")
+    }
+
+    async fn write(&mut self, data: &[u8]) -> Result<()> {
+        self.file.write_all(data).await?;
+        Ok(())
+    }
+
+    async fn finish(mut self) -> Result<PathBuf> {
+        self.file.flush().await?;
+        fs::rename(&self.part, &self.path).await?;
+        Ok(self.path)
+    }
+
+    async fn abort(self) {
+        drop(self.file);
+        let _ = fs::remove_file(&self.part).await;
+    }
+}
+
+/// reads more of the body onto the end of `buf`, false at the end of the body
+async fn fill<T: AsyncBufRead + Unpin>(body: &mut Body<'_, T>, buf: &mut Vec<u8>) -> Result<bool> {
+    let mut chunk = [0; 16 * 1024];
+    let n = body.read(&mut chunk).await?;
+    buf.extend_from_slice(&chunk[.. n]);
+    Ok(n > 0)
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+/// `key=value` parameter of a header such as Content-Disposition or Content-Type
+fn header_param(header: &str, key: &str) -> Option<String> {
+    header.split(';')
+        .filter_map(|param| param.trim().split_once('='))
+        .find(|(k, _)| k.trim().eq_ignore_ascii_case(key))
+        .map(|(_, v)| v.trim().trim_matches('"').to_string())
+}
+
+/// fails for a file name that is not of the `kind` an upload is restricted to
+fn check_kind(name: &str, kind: Option<media::Kind>) -> Result<()> {
+    match kind {
+        Some(kind) if media::Kind::from_path(Path::new(name)) != kind => {
+            bail!("{name:?} is not a {} file", format!("{kind:?}").to_lowercase())
+        },
+        _ => Ok(()),
+    }
+}
+
+/// Saves every file part of a multipart/form-data body, streaming each part to disk. If a part
+/// fails, the files saved before it are removed again.
+async fn receive_multipart<T>(body: &mut Body<'_, T>, boundary: &str, dir: &Path, kind: Option<media::Kind>) -> Result<Vec<PathBuf>>
+where
+    T: AsyncBufRead + Unpin,
+{
+    let mut saved = Vec::new();
+    match receive_parts(body, boundary, dir, kind, &mut saved).await {
+        Ok(()) => Ok(saved),
+        Err(e) => {
+            for file in &saved {
+                let _ = fs::remove_file(file).await;
+            }
+            Err(e)
+        },
+    }
+}
+
+async fn receive_parts<T>(body: &mut Body<'_, T>, boundary: &str, dir: &Path, kind: Option<media::Kind>, saved: &mut Vec<PathBuf>) -> Result<()>
+where
+    T: AsyncBufRead + Unpin,
+{
+    // the leading CRLF lets the first boundary match the same delimiter as the others
+    let delimiter = format!("\r\n--{boundary}").into_bytes();
+    let mut buf = b"\r\n".to_vec();
+    loop {
+        // find the next boundary
+        let start = loop {
+            if let Some(i) = find(&buf, &delimiter) {
+                break i;
+            }
+            let keep = buf.len().saturating_sub(delimiter.len());
+            buf.drain(.. keep);
+            if !fill(body, &mut buf).await? {
+                bail!("multipart body ended before its closing boundary");
+            }
+        };
+        buf.drain(.. start + delimiter.len());
+        while buf.len() < 2 {
+            if !fill(body, &mut buf).await? {
+                bail!("multipart body ended before its closing boundary");
+            }
+        }
+        if buf.starts_with(b"--") {
+            return Ok(());
+        }
+        // part headers
+        let header_end = loop {
+            if let Some(i) = find(&buf, b"\r\n\r\n") {
+                break i;
+            }
+            if buf.len() > MAX_PART_HEADER {
+                bail!("multipart headers too long");
+            }
+            if !fill(body, &mut buf).await? {
+                bail!("multipart body ended in part headers");
+            }
+        };
+        let headers = String::from_utf8_lossy(&buf[.. header_end]).into_owned();
+        buf.drain(.. header_end + 4);
+        let filename = headers.split("\r\n")
+            .filter_map(|line| line.split_once(':'))
+            .find(|(key, _)| key.trim().eq_ignore_ascii_case("Content-Disposition"))
+            .and_then(|(_, val)| header_param(val, "filename"));
+        let Some(filename) = filename else {
+            // plain form fields are skipped by the next boundary search
+            continue;
+        };
+        check_kind(&safe_name(&filename), kind)?;
+        let mut upload = Upload::create(dir, &filename).await?;
+        let result = async {
+            loop {
+                if let Some(i) = find(&buf, &delimiter) {
+                    upload.write(&buf[.. i]).await?;
+                    buf.drain(.. i);
+                    return Ok(());
+                }
+                // everything except a possible partial delimiter at the end is file data
+                let keep = buf.len().saturating_sub(delimiter.len());
+                upload.write(&buf[.. keep]).await?;
+                buf.drain(.. keep);
+                if !fill(body, &mut buf).await? {
+                    bail!("multipart body ended in a file part");
+                }
+            }
+        }.await;
+        match result {
+            Ok(()) => saved.push(upload.finish().await?),
+            Err(e) => {
+                upload.abort().await;
+                return Err(e);
+            },
+        }
+    }
+}
+
+/// Saves a raw request body as a single file.
+async fn receive_raw<T>(body: &mut Body<'_, T>, name: &str, dir: &Path, kind: Option<media::Kind>) -> Result<Vec<PathBuf>>
+where
+    T: AsyncBufRead + Unpin,
+{
+    check_kind(&safe_name(name), kind)?;
+    let mut upload = Upload::create(dir, name).await?;
+    let mut buf = [0; 16 * 1024];
+    loop {
+        let n = match body.read(&mut buf).await {
+            Ok(n) => n,
+            Err(e) => {
+                upload.abort().await;
+                return Err(e);
+            },
+        };
+        if n == 0 {
+            return Ok(vec![upload.finish().await?]);
+        }
+        if let Err(e) = upload.write(&buf[.. n]).await {
+            upload.abort().await;
+            return Err(e);
+        }
+    }
+}
+
+/// Stores the files of an upload request in `dir`. The body is either multipart/form-data or the
+/// raw file, named by the `name` query parameter. With a `kind`, files of any other kind fail the
+/// upload before they are written.
+pub async fn receive<T>(request: &Request, stream: &mut T, dir: &Path, limit: u64, kind: Option<media::Kind>) -> Result<Vec<PathBuf>>
+where
+    T: AsyncBufRead + Unpin,
+{
+    fs::create_dir_all(dir).await?;
+    let mut body = request.body(stream, limit)?;
+    let content_type = request.header("Content-Type").map_or("", |t| t.as_str());
+    if content_type.starts_with("multipart/form-data") {
+        let boundary = header_param(content_type, "boundary")
+            .ok_or(anyhow!("multipart boundary missing"))?;
+        receive_multipart(&mut body, &boundary, dir, kind).await
+    } else {
+        let name = request.query().get("name").cloned().unwrap_or_default();
+        receive_raw(&mut body, &name, dir, kind).await
+    }
+}
+
+/// Writes the JSON result of an upload like the `/api` routes do, answering 413 when the upload
+/// is over its size limit.
+pub async fn respond<T>(request: &Request, stream: &mut T, result: Result<serde_json::Value>) -> Result<()>
+where
+    T: AsyncWrite + Unpin,
+{
+    let data = match result {
+        Ok(data) => data,
+        Err(e) => {
+            warning!("upload failed: {e}");
+            let status = if e.is::<PayloadTooLarge>() { 413 } else { 400 };
+            return json_error(request, stream, status, &e.to_string()).await;
+        },
+    };
+    let payload = json!({ "ok": true, "data": data }).to_string();
+    let response = Response::new("HTTP/1.1", 200)
+        .headers(&request.response_headers)
+        .header("Content-Type", "application/json")
+        .header("Connection", "close")
+        .body(payload.as_bytes());
+    response.write_to(stream).await?;
+    Ok(())
+}
+
+/// `POST /api/upload?mode=replace|append-play`: stores the uploaded files in the upload directory
+/// and loads them into mpv when a mode is given, the first file with that mode and any others
+/// appended after it.
+pub async fn handle<T>(request: &Request, stream: &mut T, config: &Config, cmd_handle: &mut CmdHandle<'_>) -> Result<()>
+where
+    T: AsyncBufRead + AsyncWrite + Unpin,
+{
+    let result = async {
+        let dir = config.upload_dir.as_ref().ok_or(anyhow!("uploads are disabled, set upload_dir to enable them"))?;
+        let mode = request.query().get("mode").cloned();
+        if let Some(mode) = &mode {
+            if mode != "replace" && mode != "append-play" {
+                bail!("invalid mode \"{mode}\"");
+            }
+        }
+        let files = receive(request, stream, dir, config.max_upload_size, None).await?;
+        for (i, file) in files.iter().enumerate() {
+            info!("uploaded {file:?}");
+            let Some(mode) = &mode else { continue };
+            let mode = if i == 0 { mode.as_str() } else { "append-play" };
+            let path = file.to_str().ok_or(anyhow!("path is not utf-8"))?;
+            cmd_handle.command(["loadfile", path, mode]).map_err(|e| anyhow!(e))?;
+        }
+        Ok(json!({ "files": files }))
+    }.await;
+    respond(request, stream, result).await
+}
+
+/// largest subtitle file accepted
+const MAX_SUBTITLE_SIZE: u64 = 16 * 1024 * 1024;
+
+/// `POST /api/subtitles?flag=select|auto|cached`: stores uploaded subtitle files in the subtitle
+/// directory and adds them to the current video, `select` being the default.
+pub async fn handle_subtitles<T>(request: &Request, stream: &mut T, config: &Config, cmd_handle: &mut CmdHandle<'_>) -> Result<()>
+where
+    T: AsyncBufRead + AsyncWrite + Unpin,
+{
+    let result = async {
+        let dir = config.subtitle_dir().ok_or(anyhow!("no directory to store subtitles in, set subtitle_dir"))?;
+        let flag = request.query().get("flag").cloned().unwrap_or("select".into());
+        plugin::check_sub_flag(&flag)?;
+        let limit = config.max_upload_size.min(MAX_SUBTITLE_SIZE);
+        let subtitles = receive(request, stream, &dir, limit, Some(media::Kind::Subtitle)).await?;
+        for file in &subtitles {
+            info!("uploaded subtitle {file:?}");
+            plugin::sub_add(cmd_handle, file, &flag)?;
+        }
+        Ok(json!({ "files": subtitles }))
+    }.await;
+    respond(request, stream, result).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+    use tokio::io::BufReader;
+
+    #[test]
+    fn safe_names() {
+        assert_eq!(safe_name("../../etc/passwd"), "passwd");
+        assert_eq!(safe_name("C:\\Users\\me\\clip.mp4"), "clip.mp4");
+        assert_eq!(safe_name(".bashrc"), "bashrc");
+        assert_eq!(safe_name("a\u{0}b?.mkv"), "ab.mkv");
+        assert_eq!(safe_name(".."), "upload");
+    }
+
+    #[tokio::test]
+    async fn concurrent_names() {
+        let dir = TempDir::new("upload-names");
+        std::fs::write(dir.join("clip.mp4"), b"").unwrap();
+        // both uploads are open at once, so neither can take the other's name
+        let first = Upload::create(&dir, "clip.mp4").await.unwrap();
+        let second = Upload::create(&dir, "clip.mp4").await.unwrap();
+        assert_eq!(first.path, dir.join("clip (1).mp4"));
+        assert_eq!(second.path, dir.join("clip (2).mp4"));
+        assert_eq!(second.finish().await.unwrap(), dir.join("clip (2).mp4"));
+        first.abort().await;
+        assert!(!dir.join("clip (1).mp4.part").exists());
+        assert_eq!(candidate_names("notes").nth(1).unwrap(), "notes (1)");
+    }
+
+    #[tokio::test]
+    async fn multipart_upload() {
+        let dir = TempDir::new("upload");
+        let body = "--XyZ\r\nContent-Disposition: form-data; name=\"mode\"\r\n\r\nreplace\r\n\
+            --XyZ\r\nContent-Disposition: form-data; name=\"file\"; filename=\"../clip.mp4\"\r\n\
+            Content-Type: video/mp4\r\n\r\nline one\r\n--Xy\r\n--XyZ--\r\n";
+        let raw = format!("POST /api/upload HTTP/1.1\r\nContent-Type: multipart/form-data; boundary=XyZ\r\n\
+            Content-Length: {}\r\n\r\n{body}", body.len());
+        let mut stream = BufReader::new(std::io::Cursor::new(raw.into_bytes()));
+        let request = Request::parse(&mut stream).await.unwrap();
+        let files = receive(&request, &mut stream, &dir, 1024, None).await.unwrap();
+        assert_eq!(files, vec![dir.join("clip.mp4")]);
+        assert_eq!(std::fs::read(&files[0]).unwrap(), b"line one\r\n--Xy");
+    }
+
+    #[tokio::test]
+    async fn subtitles_only() {
+        let dir = TempDir::new("subtitles");
+        let body = "--XyZ\r\nContent-Disposition: form-data; name=\"file\"; filename=\"a.srt\"\r\n\r\n1\r\n\
+            --XyZ\r\nContent-Disposition: form-data; name=\"file\"; filename=\"c.sh\"\r\n\r\nrm\r\n--XyZ--\r\n";
+        let raw = format!("POST /api/subtitles HTTP/1.1\r\nContent-Type: multipart/form-data; boundary=XyZ\r\n\
+            Content-Length: {}\r\n\r\n{body}", body.len());
+        let mut stream = BufReader::new(std::io::Cursor::new(raw.into_bytes()));
+        let request = Request::parse(&mut stream).await.unwrap();
+        let result = receive(&request, &mut stream, &dir, 1024, Some(media::Kind::Subtitle)).await;
+        assert!(result.unwrap_err().to_string().contains("not a subtitle file"));
+        // neither the script nor the subtitle saved before it are left behind
+        assert_eq!(std::fs::read_dir(&*dir).unwrap().count(), 0);
+    }
+}
@@ -1,7 +1,7 @@
 use crate::server::{Request, Response};
 use anyhow::{Result, anyhow, bail};
 use std::{fmt::Debug, io};
-use tokio::io::{copy, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, Take};
+use tokio::io::{copy, AsyncRead, AsyncReadExt, AsyncWrite, Take};
 use byteorder::{ByteOrder, NetworkEndian, ReadBytesExt, WriteBytesExt};
 use sha1::{self, Digest};
 use base64::Engine;
@@ -33,7 +33,7 @@ impl<T: AsyncRead + AsyncWrite + Unpin> WebSocketServer<T> {
             .header("Upgrade", "websocket")
             .header("Connection", "Upgrade")
             .header("Sec-WebSocket-Accept", &ws_accept);
-        stream.write_all(&response.bytes()).await?;
+        response.write_to(&mut stream).await?;
         Ok(WebSocketServer(stream))
     }
 
@@ -64,7 +64,8 @@ impl<T: AsyncRead + AsyncWrite + Unpin> WebSocketServer<T> {
             MessageType::Close(code) => {
                 let mut code_bytes: [u8; 2] = [0; 2];
                 NetworkEndian::write_u16(&mut code_bytes, code);
-                let payload_len = msg.data.limit();
+                // the payload is the status code followed by the reason
+                let payload_len = msg.data.limit() + code_bytes.len() as u64;
                 let frame = Frame {
                     fin: true,
                     opcode,
@@ -170,7 +171,7 @@ impl<'a> Message<&'a [u8]> {
         }
     }
 
-    fn close(status: CloseStatus, reason: &'a str) -> Self {
+    pub fn close(status: CloseStatus, reason: &'a str) -> Self {
         let len = reason.len() as u64;
         let bytes = reason.as_bytes();
         Message {
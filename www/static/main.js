@@ -142,7 +142,8 @@ const initSocket = () => {
             this.connect();
         },
         connect: function() {
-            this.ws = new WebSocket("ws://" + window.location.host + "/socket");
+            const scheme = window.location.protocol === "https:" ? "wss://" : "ws://";
+            this.ws = new WebSocket(scheme + window.location.host + "/socket");
             this.ws.addEventListener("open", (event) => {
                 console.debug("socket connected: ", this.ws);
                 ui["sock-conn"] = 1;
@@ -241,13 +242,17 @@ async function renderDirectory(dir) {
     const action = window.location.hash.substring(1);
     const resp = await fetch("/file-picker/" + dir);
     const directory = await resp.json();
+    if (!resp.ok) {
+        console.error("file picker: ", directory.error);
+        return;
+    }
     let html = `<ul class="font-lg divide-y divide-gray-200 dark:divide-gray-700">`;
     if (dir !== "") {
         html += `
             <li class="p-2" onclick="renderDirectory('${dir}'.substr(0, '${dir}'.lastIndexOf('\/')))">../</li>
             `;
     }
-    for (d of directory.dirs.sort()) {
+    for (const {name: d} of directory.dirs) {
         if (dir === "") {
             html += `
                 <li class="p-2" onclick="renderDirectory('${d}')">${d}</li>
@@ -258,15 +263,67 @@ async function renderDirectory(dir) {
                 `;
         }
     }
-    for (f of directory.files.sort()) {
+    for (const {name: f, kind} of directory.files) {
+        // subtitles are added to the current video instead of being played
+        const event = kind === "subtitle" ? "sub-add" : action;
         html += `
-            <li class="p-2"><a href="#" onclick="socket.send({event:'${action}', data:{ file: { dir: '${dir}', name: '${f}'}}})">${f}</a></li>
+            <li class="p-2"><a href="#" onclick="socket.send({event:'${event}', data:{ file: { dir: '${dir}', name: '${f}'}}})">${f}</a></li>
             `;
     }
     html += `</ul>`;
     document.querySelector("#file-browser").innerHTML = html;
 }
 
+async function importPlaylist(file, mode) {
+    const resp = await fetch("/api/playlist/import?mode=" + mode, {method: "POST", body: file});
+    const result = await resp.json();
+    if (!result.ok) {
+        console.error("playlist import: ", result.error);
+        return;
+    }
+    for (const {entry, reason} of result.data.skipped) {
+        console.warn("playlist import skipped ", entry, ": ", reason);
+    }
+}
+
+async function uploadSubtitle(file) {
+    const resp = await fetch("/api/subtitles?flag=select&name=" + encodeURIComponent(file.name), {method: "POST", body: file});
+    const result = await resp.json();
+    if (!result.ok) {
+        console.error("subtitle upload: ", result.error);
+    }
+}
+
+async function uploadFiles(files) {
+    if (!files.length) return;
+    let mode = window.location.hash === "#play-now" ? "replace" : "append-play";
+    const form = new FormData();
+    for (const f of files) {
+        // playlists are loaded from the files they list rather than stored
+        if (/\.(m3u8?|pls|xspf)$/i.test(f.name)) {
+            await importPlaylist(f, mode);
+            mode = "append-play";
+            continue;
+        }
+        if (/\.(srt|ass|ssa|vtt|sub|idx|sup)$/i.test(f.name)) {
+            await uploadSubtitle(f);
+            continue;
+        }
+        form.append("file", f);
+    }
+    if (!form.has("file")) {
+        window.location.hash = "";
+        return;
+    }
+    const resp = await fetch("/api/upload?mode=" + mode, {method: "POST", body: form});
+    const result = await resp.json();
+    if (!result.ok) {
+        console.error("upload: ", result.error);
+        return;
+    }
+    window.location.hash = "";
+}
+
 function formatTime(seconds) {
     if (typeof(seconds) !== "number") return "--";
     seconds = Math.round(seconds);
@@ -298,6 +355,7 @@ const state = initState({
     "time-pos" : null,
     "core-idle" : null,
     "volume" : null,
+    "artwork" : null,
 });
 var timer = null;
 CallbackToBind = () => {
@@ -315,6 +373,10 @@ state["core-idle"]
 CallbackToBind = null;
 
 initTabs(document);
+// lets the remote be installed to the home screen and start without a connection
+if ("serviceWorker" in navigator) {
+    navigator.serviceWorker.register("/sw.js").catch((err) => console.error("service worker: ", err));
+}
 const socket = initSocket();
 socket.connect();
 init();